@@ -61,9 +61,37 @@ fn bench_store_update_read_and_delete_json(crit: &mut Criterion) {
     drop_table("test7").unwrap();
 }
 
+fn bench_find_in_large_table(crit: &mut Criterion) {
+    let records = (0..10_000).map(|n| Coordinates { x: n, y: n }).collect();
+
+    create_empty_table::<Coordinates>("test8").unwrap();
+    clear_table::<Coordinates>("test8").unwrap();
+    batch_insert("test8", records).unwrap();
+
+    crit.bench_function("find in large table", |b| {
+        b.iter(|| find::<Coordinates>("test8", "5000").unwrap())
+    });
+
+    crit.bench_function("get_table_records and remove in large table", |b| {
+        b.iter(|| {
+            get_table_records::<Coordinates>("test8")
+                .unwrap()
+                .remove("5000")
+                .unwrap()
+        })
+    });
+
+    crit.bench_function("json_find in large table", |b| {
+        b.iter(|| json_find::<Coordinates>("test8", "5000").unwrap())
+    });
+
+    drop_table("test8").unwrap();
+}
+
 fn combined_benchmarks(c: &mut Criterion) {
     bench_create_table(c);
     bench_find(c);
+    bench_find_in_large_table(c);
     bench_json_find(c);
     bench_json_table_records(c);
     bench_read_table(c);
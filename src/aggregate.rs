@@ -0,0 +1,576 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Aggregation module.
+//!
+//! Aggregates read a value out of every record, either with a closure or with a JSON path built
+//! by `field`, and fold them into counts, sums, extremes or averages, optionally per group.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::Result;
+use super::{get_table_records, json_pointer};
+
+type Filter<'q, T> = Box<dyn Fn(&T) -> bool + 'q>;
+type Groups<K, T> = BTreeMap<K, Vec<(String, T)>>;
+
+/// Reads the value an aggregate works on out of a record.
+///
+/// Closures taking a record implement this, and so does the JSON path returned by `field`.
+/// Records yielding `None` are left out.
+pub trait Extract<T> {
+    type Output;
+
+    fn extract(&self, record: &T) -> Option<Self::Output>;
+}
+
+impl<T, K, F> Extract<T> for F
+where
+    F: Fn(&T) -> K,
+{
+    type Output = K;
+
+    fn extract(&self, record: &T) -> Option<K> {
+        Some(self(record))
+    }
+}
+
+/// A JSON path into a record, built by `field`.
+pub struct Field {
+    pointer: String,
+}
+
+/// Extracts the value a record holds at `path`, a top-level field name or a JSON pointer.
+///
+/// Records missing the value, or holding `null`, are left out.
+pub fn field(path: &str) -> Field {
+    Field {
+        pointer: json_pointer(path),
+    }
+}
+
+impl<T: Serialize> Extract<T> for Field {
+    type Output = FieldValue;
+
+    fn extract(&self, record: &T) -> Option<FieldValue> {
+        match serde_json::to_value(record).ok()?.pointer(&self.pointer) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(FieldValue(value.clone())),
+        }
+    }
+}
+
+/// A value read by `field`, totally ordered so it can be compared and grouped on.
+///
+/// Booleans sort before numbers, numbers numerically before strings, and strings before arrays
+/// and objects, which compare by their JSON text. Values are equal when they sort the same, so
+/// `1` and `1.0` are one value.
+#[derive(Clone, Debug)]
+pub struct FieldValue(pub Value);
+
+impl FieldValue {
+    fn rank(&self) -> u8 {
+        match self.0 {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) | Value::Object(_) => 4,
+        }
+    }
+}
+
+impl PartialEq for FieldValue {
+    fn eq(&self, other: &FieldValue) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FieldValue {}
+
+impl Ord for FieldValue {
+    fn cmp(&self, other: &FieldValue) -> Ordering {
+        match (&self.0, &other.0) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Number(a), Value::Number(b)) => {
+                let (a, b) = (
+                    a.as_f64().unwrap_or_default(),
+                    b.as_f64().unwrap_or_default(),
+                );
+
+                a.total_cmp(&b)
+            }
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (a, b) => self
+                .rank()
+                .cmp(&other.rank())
+                .then_with(|| a.to_string().cmp(&b.to_string())),
+        }
+    }
+}
+
+impl PartialOrd for FieldValue {
+    fn partial_cmp(&self, other: &FieldValue) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A value that sums and averages as a number.
+pub trait Number {
+    fn to_f64(&self) -> Option<f64>;
+
+    /// The value as a whole number, if it is one. Sums stay whole while every value added is.
+    fn to_i64(&self) -> Option<i64> {
+        None
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ty),*) => {
+        $(
+            impl Number for $t {
+                fn to_f64(&self) -> Option<f64> {
+                    Some(*self as f64)
+                }
+
+                fn to_i64(&self) -> Option<i64> {
+                    i64::try_from(*self).ok()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_float {
+    ($($t:ty),*) => {
+        $(
+            impl Number for $t {
+                fn to_f64(&self) -> Option<f64> {
+                    Some(*self as f64)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl_float!(f32, f64);
+
+impl<N: Number> Number for Option<N> {
+    fn to_f64(&self) -> Option<f64> {
+        self.as_ref()?.to_f64()
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        self.as_ref()?.to_i64()
+    }
+}
+
+impl Number for FieldValue {
+    fn to_f64(&self) -> Option<f64> {
+        self.0.as_f64()
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        self.0.as_i64()
+    }
+}
+
+/// The total returned by `sum`.
+///
+/// It stays an integer while every value added is one and the total fits in an `i64`, and turns
+/// into a float as soon as either stops being true.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sum {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Sum {
+    /// The total as a float, whichever kind it is.
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Sum::Integer(n) => n as f64,
+            Sum::Float(n) => n,
+        }
+    }
+
+    fn add<N: Number>(self, n: &N) -> Option<Sum> {
+        let float = n.to_f64()?;
+
+        Some(match (self, n.to_i64()) {
+            (Sum::Integer(sum), Some(n)) => match sum.checked_add(n) {
+                Some(sum) => Sum::Integer(sum),
+                None => Sum::Float(sum as f64 + float),
+            },
+            (sum, _) => Sum::Float(sum.as_f64() + float),
+        })
+    }
+}
+
+/// A lazily built aggregate over the records of one table.
+///
+/// Nothing is read until one of the aggregating methods is called.
+pub struct Aggregate<'q, T> {
+    table: String,
+    filters: Vec<Filter<'q, T>>,
+    marker: PhantomData<T>,
+}
+
+/// Starts an aggregate over the records of `table`.
+pub fn aggregate<'q, T>(table: &str) -> Aggregate<'q, T>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    Aggregate {
+        table: table.to_string(),
+        filters: Vec::new(),
+        marker: PhantomData,
+    }
+}
+
+impl<'q, T> Aggregate<'q, T>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    /// Keeps only the records matching `predicate`. Several filters must all match.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'q,
+    {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Splits the records into groups sharing the same `key`.
+    pub fn group_by<G>(self, key: G) -> Grouped<'q, T, G>
+    where
+        G: Extract<T>,
+        G::Output: Ord,
+    {
+        Grouped {
+            aggregate: self,
+            key,
+        }
+    }
+
+    /// Counts the records.
+    pub fn count(self) -> Result<usize> {
+        Ok(self.records()?.len())
+    }
+
+    /// Counts the records sharing each `key`.
+    pub fn count_by<G>(self, key: G) -> Result<BTreeMap<G::Output, usize>>
+    where
+        G: Extract<T>,
+        G::Output: Ord,
+    {
+        self.group_by(key).count()
+    }
+
+    /// Adds up `value` over the records.
+    pub fn sum<V>(self, value: V) -> Result<Sum>
+    where
+        V: Extract<T>,
+        V::Output: Number,
+    {
+        Ok(fold_sum(&self.records()?, &value).0)
+    }
+
+    /// Returns the smallest `value` among the records.
+    pub fn min<V>(self, value: V) -> Result<Option<V::Output>>
+    where
+        V: Extract<T>,
+        V::Output: PartialOrd,
+    {
+        Ok(fold_extreme(&self.records()?, &value, Ordering::Less))
+    }
+
+    /// Returns the largest `value` among the records.
+    pub fn max<V>(self, value: V) -> Result<Option<V::Output>>
+    where
+        V: Extract<T>,
+        V::Output: PartialOrd,
+    {
+        Ok(fold_extreme(&self.records()?, &value, Ordering::Greater))
+    }
+
+    /// Returns the mean of `value` over the records, or `None` if there are none.
+    pub fn average<V>(self, value: V) -> Result<Option<f64>>
+    where
+        V: Extract<T>,
+        V::Output: Number,
+    {
+        Ok(average(fold_sum(&self.records()?, &value)))
+    }
+
+    fn records(&self) -> Result<Vec<(String, T)>> {
+        Ok(get_table_records::<T>(&self.table)?
+            .into_iter()
+            .filter(|(_, record)| self.filters.iter().all(|filter| filter(record)))
+            .collect())
+    }
+}
+
+/// An aggregate split into groups, built by `Aggregate::group_by`.
+///
+/// Every result is keyed by group, in key order. Records without a key are left out.
+pub struct Grouped<'q, T, G> {
+    aggregate: Aggregate<'q, T>,
+    key: G,
+}
+
+impl<T, G> Grouped<'_, T, G>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    G: Extract<T>,
+    G::Output: Ord,
+{
+    /// Returns the records of each group, in id order.
+    pub fn records(self) -> Result<Groups<G::Output, T>> {
+        let mut groups: Groups<G::Output, T> = BTreeMap::new();
+
+        for (id, record) in self.aggregate.records()? {
+            if let Some(key) = self.key.extract(&record) {
+                groups.entry(key).or_default().push((id, record));
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Counts the records of each group.
+    pub fn count(self) -> Result<BTreeMap<G::Output, usize>> {
+        self.fold(|records| Some(records.len()))
+    }
+
+    /// Adds up `value` over each group.
+    pub fn sum<V>(self, value: V) -> Result<BTreeMap<G::Output, Sum>>
+    where
+        V: Extract<T>,
+        V::Output: Number,
+    {
+        self.fold(|records| Some(fold_sum(records, &value).0))
+    }
+
+    /// Returns the smallest `value` of each group.
+    pub fn min<V>(self, value: V) -> Result<BTreeMap<G::Output, V::Output>>
+    where
+        V: Extract<T>,
+        V::Output: PartialOrd,
+    {
+        self.fold(|records| fold_extreme(records, &value, Ordering::Less))
+    }
+
+    /// Returns the largest `value` of each group.
+    pub fn max<V>(self, value: V) -> Result<BTreeMap<G::Output, V::Output>>
+    where
+        V: Extract<T>,
+        V::Output: PartialOrd,
+    {
+        self.fold(|records| fold_extreme(records, &value, Ordering::Greater))
+    }
+
+    /// Returns the mean of `value` over each group.
+    pub fn average<V>(self, value: V) -> Result<BTreeMap<G::Output, f64>>
+    where
+        V: Extract<T>,
+        V::Output: Number,
+    {
+        self.fold(|records| average(fold_sum(records, &value)))
+    }
+
+    fn fold<R, F>(self, f: F) -> Result<BTreeMap<G::Output, R>>
+    where
+        F: Fn(&[(String, T)]) -> Option<R>,
+    {
+        Ok(self
+            .records()?
+            .into_iter()
+            .filter_map(|(key, records)| f(&records).map(|result| (key, result)))
+            .collect())
+    }
+}
+
+// Values which are not numbers are left out
+fn fold_sum<T, V>(records: &[(String, T)], value: &V) -> (Sum, usize)
+where
+    V: Extract<T>,
+    V::Output: Number,
+{
+    records
+        .iter()
+        .filter_map(|(_, record)| value.extract(record))
+        .fold((Sum::Integer(0), 0), |(sum, count), n| match sum.add(&n) {
+            Some(sum) => (sum, count + 1),
+            None => (sum, count),
+        })
+}
+
+fn fold_extreme<T, V>(records: &[(String, T)], value: &V, keep: Ordering) -> Option<V::Output>
+where
+    V: Extract<T>,
+    V::Output: PartialOrd,
+{
+    records
+        .iter()
+        .filter_map(|(_, record)| value.extract(record))
+        .fold(None, |best, candidate| match best {
+            Some(best) if candidate.partial_cmp(&best) != Some(keep) => Some(best),
+            _ => Some(candidate),
+        })
+}
+
+fn average((sum, count): (Sum, usize)) -> Option<f64> {
+    match count {
+        0 => None,
+        count => Some(sum.as_f64() / count as f64),
+    }
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_aggregate {
+    use super::*;
+    use crate::{batch_insert, create_empty_table, drop_table};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Sale {
+        pub region: String,
+        pub amount: i32,
+    }
+
+    fn sale(region: &str, amount: i32) -> Sale {
+        Sale {
+            region: region.to_string(),
+            amount,
+        }
+    }
+
+    fn create_sales(table: &str) -> Result<()> {
+        create_empty_table::<Sale>(table)?;
+
+        batch_insert(
+            table,
+            vec![
+                sale("north", 10),
+                sale("south", 5),
+                sale("north", 30),
+                sale("east", 7),
+                sale("south", 15),
+            ],
+        )
+    }
+
+    #[test]
+    fn can_aggregate_with_closures() -> Result<()> {
+        let table = "aggregate_closure_test";
+
+        create_sales(table)?;
+
+        assert_eq!(aggregate::<Sale>(table).count()?, 5);
+
+        assert_eq!(
+            aggregate::<Sale>(table).sum(|s: &Sale| s.amount)?,
+            Sum::Integer(67)
+        );
+
+        assert_eq!(
+            aggregate::<Sale>(table).sum(|s: &Sale| s.amount as f64 / 2.0)?,
+            Sum::Float(33.5)
+        );
+
+        assert_eq!(aggregate::<Sale>(table).min(|s: &Sale| s.amount)?, Some(5));
+
+        assert_eq!(aggregate::<Sale>(table).max(|s: &Sale| s.amount)?, Some(30));
+
+        let average = aggregate::<Sale>(table)
+            .filter(|s| s.region == "south")
+            .average(|s: &Sale| s.amount)?;
+
+        assert_eq!(average, Some(10.0));
+
+        let counts = aggregate::<Sale>(table).count_by(|s: &Sale| s.region.clone())?;
+
+        let expected: BTreeMap<String, usize> = [("east", 1), ("north", 2), ("south", 2)]
+            .into_iter()
+            .map(|(region, n)| (region.to_string(), n))
+            .collect();
+
+        assert_eq!(counts, expected);
+
+        let sums = aggregate::<Sale>(table)
+            .filter(|s| s.amount > 5)
+            .group_by(|s: &Sale| s.region.clone())
+            .sum(|s: &Sale| s.amount)?;
+
+        assert_eq!(sums.get("north"), Some(&Sum::Integer(40)));
+        assert_eq!(sums.get("south"), Some(&Sum::Integer(15)));
+
+        let groups = aggregate::<Sale>(table)
+            .group_by(|s: &Sale| s.region.clone())
+            .records()?;
+
+        let north: Vec<&str> = groups["north"].iter().map(|(id, _)| id.as_str()).collect();
+
+        assert_eq!(north, vec!["0", "2"]);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_aggregate_with_paths() -> Result<()> {
+        let table = "aggregate_path_test";
+
+        create_sales(table)?;
+
+        assert_eq!(
+            aggregate::<Sale>(table).sum(field("amount"))?,
+            Sum::Integer(67)
+        );
+
+        let max = aggregate::<Sale>(table).max(field("/amount"))?;
+
+        assert_eq!(max, Some(FieldValue(Value::from(30))));
+
+        let averages = aggregate::<Sale>(table)
+            .group_by(field("region"))
+            .average(field("amount"))?;
+
+        assert_eq!(averages[&FieldValue(Value::from("north"))], 20.0);
+        assert_eq!(averages[&FieldValue(Value::from("east"))], 7.0);
+
+        let maxima = aggregate::<Sale>(table)
+            .group_by(field("region"))
+            .max(|s: &Sale| s.amount)?;
+
+        assert_eq!(maxima[&FieldValue(Value::from("south"))], 15);
+
+        assert_eq!(
+            aggregate::<Sale>(table).sum(field("missing"))?,
+            Sum::Integer(0)
+        );
+
+        assert_eq!(FieldValue(Value::from(1)), FieldValue(Value::from(1.0)));
+        assert_ne!(FieldValue(Value::from(1)), FieldValue(Value::from("1")));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,865 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Backup module.
+//!
+//! A backup is a tar archive of every file in the database, tables, namespaces and the hidden
+//! files kept alongside them alike, under `db/`. It ends with `MANIFEST.json`, which lists the
+//! size and CRC-32 of each file. The archive can be unpacked with any tar tool. With the
+//! `compression` feature it can be gzipped as well.
+//!
+//! Backups are taken under the write lock, so they capture the database between two writes of
+//! this process. Writes made by other processes are not held off.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, DB_PATH, SIDECARS, expiry, feed, get_table, list_namespaces, list_tables,
+    list_tables_in, lock_writes, move_file, references, table_exists, tmp_table,
+};
+
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "MANIFEST.json";
+
+const BLOCK: usize = 512;
+
+/// What an archive holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// When the backup was taken, in milliseconds since the Unix epoch.
+    pub created: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// One file of an archive, with its path relative to the database directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// How `restore` treats the database it restores into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestoreMode {
+    /// The whole database is replaced by the archive, dropping tables it does not hold.
+    Replace,
+    /// Only the tables in the archive are replaced, leaving every other table as it is.
+    Merge,
+}
+
+/// Writes a consistent snapshot of the whole database to the archive `dest`, returning its
+/// manifest.
+///
+/// The archive only appears at `dest` once it is complete.
+pub fn backup<P: AsRef<Path>>(dest: P) -> Result<Manifest> {
+    let _lock = lock_writes()?;
+
+    write_archive(dest.as_ref(), &db_files()?, false)
+}
+
+/// Like `backup`, but gzips the archive.
+#[cfg(feature = "compression")]
+pub fn backup_compressed<P: AsRef<Path>>(dest: P) -> Result<Manifest> {
+    let _lock = lock_writes()?;
+
+    write_archive(dest.as_ref(), &db_files()?, true)
+}
+
+/// Restores the database from the archive `archive`, returning its manifest.
+///
+/// Every file is unpacked and checked against the manifest before any of the database is
+/// touched, so a damaged archive fails with `Error::CorruptArchive` and changes nothing. Replace
+/// swaps the whole database directory, while Merge puts each file of the restored tables in place
+/// with a rename of its own. A restore cut short by a crash is finished or undone before the next
+/// write. Subscribers see each restored table cleared and filled again, and the tables a
+/// replace leaves out dropped.
+pub fn restore<P: AsRef<Path>>(archive: P, mode: RestoreMode) -> Result<Manifest> {
+    let _lock = lock_writes()?;
+
+    let staging = beside_db(".db.restore");
+
+    remove_dir(&staging)?;
+
+    let mut dropped = BTreeSet::new();
+
+    let restored = unpack(archive.as_ref(), &staging).and_then(|manifest| {
+        match mode {
+            RestoreMode::Replace => {
+                dropped = all_tables()?;
+
+                replace(&staging)?;
+            }
+            RestoreMode::Merge => merge(&staging, &manifest)?,
+        }
+
+        Ok(manifest)
+    });
+
+    remove_dir(&staging)?;
+
+    let manifest = restored?;
+
+    for table in dropped.difference(&tables(&manifest)) {
+        feed::publish_drop(table);
+    }
+
+    for table in tables(&manifest) {
+        if !table_exists(&table) {
+            continue;
+        }
+
+        let mut changes = vec![Change::Clear];
+
+        for (id, new) in get_table::<Value>(&table)?.records {
+            changes.push(Change::Insert { id, new });
+        }
+
+        feed::publish(&table, &changes);
+    }
+
+    Ok(manifest)
+}
+
+// Writing ****************************************************************************************
+
+// Every file of the database, relative to its directory, leaving out files still being written
+fn db_files() -> Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+
+            // Files are written as hidden `.tmp` files and renamed into place, so only those are
+            // half written. A table may well be named `something.tmp` itself.
+            if name.starts_with('.') && name.ends_with(".tmp") {
+                continue;
+            }
+
+            let path = format!("{}{}", prefix, name);
+
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{}/", path), files)?;
+            } else {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+
+    if Path::new(DB_PATH).exists() {
+        walk(Path::new(DB_PATH), "", &mut files)?;
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+fn write_archive(dest: &Path, files: &[String], compress: bool) -> Result<Manifest> {
+    let mut tmp = dest.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let tmp = PathBuf::from(tmp);
+
+    let written = File::create(&tmp)
+        .map_err(Error::from)
+        .and_then(|file| write_file(BufWriter::new(file), files, compress));
+
+    match written {
+        Ok(manifest) => {
+            fs::rename(&tmp, dest)?;
+
+            Ok(manifest)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+
+            Err(err)
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn write_file(out: BufWriter<File>, files: &[String], compress: bool) -> Result<Manifest> {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    if !compress {
+        let mut out = out;
+
+        let manifest = write_tar(&mut out, files)?;
+
+        finish(out)?;
+
+        return Ok(manifest);
+    }
+
+    let mut gz = GzEncoder::new(out, Compression::default());
+
+    let manifest = write_tar(&mut gz, files)?;
+
+    finish(gz.finish()?)?;
+
+    Ok(manifest)
+}
+
+#[cfg(not(feature = "compression"))]
+fn write_file(mut out: BufWriter<File>, files: &[String], _: bool) -> Result<Manifest> {
+    let manifest = write_tar(&mut out, files)?;
+
+    finish(out)?;
+
+    Ok(manifest)
+}
+
+fn write_tar<W: Write>(out: &mut W, files: &[String]) -> Result<Manifest> {
+    let mut manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        created: expiry::now(),
+        files: Vec::with_capacity(files.len()),
+    };
+
+    for path in files {
+        let contents = fs::read(Path::new(DB_PATH).join(path))?;
+
+        write_entry(out, &format!("db/{}", path), &contents)?;
+
+        manifest.files.push(ManifestEntry {
+            path: path.clone(),
+            size: contents.len() as u64,
+            crc32: crc32(0, &contents),
+        });
+    }
+
+    write_entry(out, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+
+    // A tar archive ends with two empty blocks
+    out.write_all(&[0; 2 * BLOCK])?;
+
+    out.flush()?;
+
+    Ok(manifest)
+}
+
+// Flushes an archive all the way to the disk
+fn finish(out: BufWriter<File>) -> Result<()> {
+    out.into_inner()
+        .map_err(|err| Error::Io(err.into_error()))?
+        .sync_all()?;
+
+    Ok(())
+}
+
+// Writes one file as a ustar header block followed by its contents, padded to a whole block
+fn write_entry<W: Write>(out: &mut W, name: &str, contents: &[u8]) -> Result<()> {
+    let (prefix, name) = split_name(name)?;
+
+    let mut header = [0; BLOCK];
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], contents.len() as u64);
+    octal(&mut header[136..148], expiry::now() / 1000);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is taken with its own field filled with spaces
+    header[148..156].fill(b' ');
+
+    let checksum = header.iter().map(|&byte| u64::from(byte)).sum();
+
+    octal(&mut header[148..155], checksum);
+
+    out.write_all(&header)?;
+    out.write_all(contents)?;
+    out.write_all(&[0; BLOCK][..padding(contents.len() as u64)])?;
+
+    Ok(())
+}
+
+// Fits a path into the 100 byte name and 155 byte prefix of a ustar header, split at a slash
+fn split_name(path: &str) -> Result<(&str, &str)> {
+    if path.len() <= 100 {
+        return Ok(("", path));
+    }
+
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+        .ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the path \"{}\" is too long for a backup", path),
+            ))
+        })
+}
+
+// Fills a header field with a zero-padded octal number and a trailing NUL
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+
+    let digits = format!("{:0width$o}", value, width = width);
+
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+// Reading ****************************************************************************************
+
+// Unpacks an archive into a directory, checking every file against the manifest
+fn unpack(archive: &Path, dir: &Path) -> Result<Manifest> {
+    let mut input = BufReader::new(File::open(archive)?);
+
+    // Gzip streams start with these two bytes, which no tar header does
+    if input.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return unpack_compressed(input, dir);
+    }
+
+    unpack_tar(input, dir)
+}
+
+#[cfg(feature = "compression")]
+fn unpack_compressed(input: BufReader<File>, dir: &Path) -> Result<Manifest> {
+    unpack_tar(BufReader::new(flate2::read::GzDecoder::new(input)), dir)
+}
+
+#[cfg(not(feature = "compression"))]
+fn unpack_compressed(_: BufReader<File>, _: &Path) -> Result<Manifest> {
+    Err(corrupt("compressed archives need the compression feature"))
+}
+
+fn unpack_tar<R: Read>(mut input: R, dir: &Path) -> Result<Manifest> {
+    fs::create_dir_all(dir)?;
+
+    let mut unpacked = BTreeMap::new();
+
+    let mut manifest = None;
+
+    while let Some((name, size)) = read_header(&mut input)? {
+        if name == MANIFEST {
+            let mut contents = Vec::new();
+
+            (&mut input).take(size).read_to_end(&mut contents)?;
+
+            manifest = Some(
+                serde_json::from_slice::<Manifest>(&contents)
+                    .map_err(|err| corrupt(&format!("the manifest cannot be read: {}", err)))?,
+            );
+        } else {
+            let path = name
+                .strip_prefix("db/")
+                .filter(|path| is_safe(path))
+                .ok_or_else(|| corrupt(&format!("unexpected entry \"{}\"", name)))?;
+
+            let target = dir.join(path);
+
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let crc = copy_entry(&mut input, size, &mut BufWriter::new(File::create(target)?))?;
+
+            unpacked.insert(path.to_string(), (size, crc));
+        }
+
+        io::copy(
+            &mut (&mut input).take(padding(size) as u64),
+            &mut io::sink(),
+        )?;
+    }
+
+    let manifest = manifest.ok_or_else(|| corrupt("the manifest is missing"))?;
+
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(corrupt(&format!(
+            "unknown format version {}",
+            manifest.format_version
+        )));
+    }
+
+    for file in &manifest.files {
+        match unpacked.remove(&file.path) {
+            Some(found) if found == (file.size, file.crc32) => {}
+            Some(_) => return Err(corrupt(&format!("\"{}\" is damaged", file.path))),
+            None => return Err(corrupt(&format!("\"{}\" is missing", file.path))),
+        }
+    }
+
+    if let Some(path) = unpacked.keys().next() {
+        return Err(corrupt(&format!("\"{}\" is not in the manifest", path)));
+    }
+
+    Ok(manifest)
+}
+
+// Reads the next header, returning the name and size of the file it describes, or `None` at the
+// end of the archive
+fn read_header<R: Read>(input: &mut R) -> Result<Option<(String, u64)>> {
+    loop {
+        let mut header = [0; BLOCK];
+
+        input
+            .read_exact(&mut header)
+            .map_err(|_| corrupt("the archive ends in the middle of an entry"))?;
+
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+
+        let stored = parse_octal(&header[148..156])?;
+
+        header[148..156].fill(b' ');
+
+        if header.iter().map(|&byte| u64::from(byte)).sum::<u64>() != stored {
+            return Err(corrupt("a header is damaged"));
+        }
+
+        let name = text(&header[..100])?;
+        let prefix = text(&header[345..500])?;
+        let size = parse_octal(&header[124..136])?;
+
+        match header[156] {
+            b'0' | 0 if prefix.is_empty() => return Ok(Some((name, size))),
+            b'0' | 0 => return Ok(Some((format!("{}/{}", prefix, name), size))),
+            // Directories are made as the files inside them are unpacked
+            b'5' => continue,
+            _ => return Err(corrupt(&format!("\"{}\" is not a regular file", name))),
+        }
+    }
+}
+
+// Copies one file out of the archive, returning its checksum
+fn copy_entry<R: Read, W: Write>(input: &mut R, size: u64, out: &mut W) -> Result<u32> {
+    let mut remaining = size;
+
+    let mut buffer = [0; 8 * 1024];
+
+    let mut crc = 0;
+
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(8 * 1024) as usize];
+
+        input
+            .read_exact(chunk)
+            .map_err(|_| corrupt("the archive ends in the middle of a file"))?;
+
+        crc = crc32(crc, chunk);
+
+        out.write_all(chunk)?;
+
+        remaining -= chunk.len() as u64;
+    }
+
+    out.flush()?;
+
+    Ok(crc)
+}
+
+fn text(field: &[u8]) -> Result<String> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+
+    String::from_utf8(field[..end].to_vec()).map_err(|_| corrupt("a name is not UTF-8"))
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let digits = text(field)?;
+
+    u64::from_str_radix(digits.trim(), 8).map_err(|_| corrupt("a header is damaged"))
+}
+
+// Rejects paths which would land outside the directory they are unpacked into
+fn is_safe(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn corrupt(msg: &str) -> Error {
+    Error::CorruptArchive(msg.to_string())
+}
+
+// Restoring **************************************************************************************
+
+// Swaps the unpacked directory in for the database directory
+fn replace(staging: &Path) -> Result<()> {
+    let old = beside_db(".db.old");
+
+    remove_dir(&old)?;
+
+    if Path::new(DB_PATH).exists() {
+        fs::rename(DB_PATH, &old)?;
+    }
+
+    fs::rename(staging, DB_PATH)?;
+
+    remove_dir(&old)
+}
+
+// Moves the tables of the unpacked directory over those of the database directory. Each file is
+// put in place with a rename, so no table is ever seen half written.
+fn merge(staging: &Path, manifest: &Manifest) -> Result<()> {
+    let tables = tables(manifest);
+
+    for table in &tables {
+        for path in table_files(table) {
+            let (from, to) = (staging.join(&path), Path::new(DB_PATH).join(&path));
+
+            if !from.exists() {
+                match fs::remove_file(&to) {
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+
+                continue;
+            }
+
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // Moved next to its place first, in case the staging directory is on another device
+            let tmp = tmp_table(&to);
+
+            move_file(&from, &tmp)?;
+
+            fs::rename(tmp, to)?;
+        }
+    }
+
+    references::merge_restored(&staging.join(".references"), &tables)
+}
+
+// The files of a table relative to the database directory, its sidecars and then the table itself
+fn table_files(table: &str) -> Vec<String> {
+    let (dir, name) = match table.rsplit_once('/') {
+        Some((namespace, name)) => (format!("{}/", namespace), name),
+        None => (String::new(), table),
+    };
+
+    let mut files: Vec<String> = SIDECARS
+        .iter()
+        .map(|kind| format!("{}.{}.{}", dir, name, kind))
+        .collect();
+
+    files.push(table.to_string());
+
+    files
+}
+
+// Finishes or undoes a restore interrupted part way through swapping the database directory.
+// Called before every write, along with the recovery of transactions.
+pub(crate) fn recover_restore() -> Result<()> {
+    let old = beside_db(".db.old");
+
+    // The old directory was moved aside but the restored one never took its place
+    if old.exists() && !Path::new(DB_PATH).exists() {
+        fs::rename(&old, DB_PATH)?;
+    }
+
+    remove_dir(&old)?;
+
+    remove_dir(&beside_db(".db.restore"))
+}
+
+// Every table of the database, by its qualified name
+fn all_tables() -> Result<BTreeSet<String>> {
+    let mut tables: BTreeSet<String> = list_tables()?.into_iter().collect();
+
+    for namespace in list_namespaces()? {
+        tables.extend(list_tables_in(&namespace)?);
+    }
+
+    Ok(tables)
+}
+
+// The tables an archive holds, by their qualified names
+fn tables(manifest: &Manifest) -> BTreeSet<String> {
+    manifest
+        .files
+        .iter()
+        .filter(|file| {
+            let name = file.path.rsplit('/').next().unwrap_or_default();
+
+            !name.starts_with('.')
+        })
+        .map(|file| file.path.clone())
+        .collect()
+}
+
+// A path next to the database directory, on the same file system so renames stay atomic
+fn beside_db(name: &str) -> PathBuf {
+    Path::new(DB_PATH).with_file_name(name)
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(Error::from),
+    }
+}
+
+// CRC-32 *****************************************************************************************
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xEDB8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
+            };
+
+            bit += 1;
+        }
+
+        table[i] = crc;
+
+        i += 1;
+    }
+
+    table
+}
+
+// Carries on the CRC-32 of some bytes, as used by zip and gzip, starting from 0
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for &byte in bytes {
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_backup {
+    use super::*;
+    use crate::{
+        append_records, create_empty_table, create_index, drop_index, drop_table, find_by_index,
+        get_table_records,
+    };
+    use serde_json::json;
+
+    // Archives only the files of one table, so restoring it leaves other tests alone
+    fn back_up_table(table: &str, dest: &Path) -> Result<Manifest> {
+        let _lock = lock_writes()?;
+
+        let hidden = format!(".{}.", table);
+
+        let files: Vec<String> = db_files()?
+            .into_iter()
+            .filter(|path| path == table || path.starts_with(&hidden))
+            .collect();
+
+        write_archive(dest, &files, false)
+    }
+
+    #[test]
+    fn can_checksum() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn can_back_up_and_merge_a_table() -> Result<()> {
+        let (table, other) = ("backup_merge_test", "backup_merge_other_test");
+        let archive = std::env::temp_dir().join("rust_bucket_backup_merge_test.tar");
+
+        create_empty_table::<Value>(other)?;
+        create_empty_table::<Value>(table)?;
+        append_records(table, json!({"name": "Ada"}))?;
+        create_index(table, "name", "name")?;
+
+        let manifest = back_up_table(table, &archive)?;
+
+        assert_eq!(manifest.files.len(), 3);
+
+        append_records(table, json!({"name": "Bob"}))?;
+        append_records(other, json!({"name": "Cy"}))?;
+        drop_index(table, "name")?;
+
+        restore(&archive, RestoreMode::Merge)?;
+
+        assert_eq!(get_table_records::<Value>(table)?.len(), 1);
+        assert_eq!(find_by_index::<Value, _>(table, "name", &"Ada")?.len(), 1);
+        assert_eq!(get_table_records::<Value>(other)?.len(), 1);
+
+        fs::remove_file(&archive)?;
+
+        drop_table(table)?;
+        drop_table(other)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_clear_up_after_an_interrupted_restore() -> Result<()> {
+        let table = "backup_interrupted_test";
+
+        create_empty_table::<Value>(table)?;
+
+        // Left behind by a restore which crashed after putting the new database in place
+        for leftover in [".db.old", ".db.restore"] {
+            fs::create_dir_all(beside_db(leftover))?;
+            fs::write(beside_db(leftover).join(table), "{}")?;
+        }
+
+        append_records(table, json!({"name": "Ada"}))?;
+
+        assert!(!beside_db(".db.old").exists());
+        assert!(!beside_db(".db.restore").exists());
+        assert_eq!(get_table_records::<Value>(table)?.len(), 1);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_a_damaged_archive() -> Result<()> {
+        let table = "backup_damaged_test";
+        let archive = std::env::temp_dir().join("rust_bucket_backup_damaged_test.tar");
+
+        create_empty_table::<Value>(table)?;
+        append_records(table, json!({"name": "Ada"}))?;
+
+        back_up_table(table, &archive)?;
+
+        append_records(table, json!({"name": "Bob"}))?;
+
+        let mut bytes = fs::read(&archive)?;
+
+        // The contents of the first file start right after its header
+        bytes[BLOCK] ^= 1;
+
+        fs::write(&archive, &bytes)?;
+
+        assert!(matches!(
+            restore(&archive, RestoreMode::Merge),
+            Err(Error::CorruptArchive(_))
+        ));
+
+        assert_eq!(get_table_records::<Value>(table)?.len(), 2);
+
+        fs::write(&archive, &bytes[..BLOCK + 10])?;
+
+        assert!(matches!(
+            restore(&archive, RestoreMode::Merge),
+            Err(Error::CorruptArchive(_))
+        ));
+
+        fs::remove_file(&archive)?;
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_back_up_the_whole_database() -> Result<()> {
+        let table = "backup_whole_test.tmp";
+        let archive = std::env::temp_dir().join("rust_bucket_backup_whole_test.tar");
+
+        create_empty_table::<Value>(table)?;
+
+        fs::write(
+            Path::new(DB_PATH).join(".backup_whole_test.tmp"),
+            "half written",
+        )?;
+
+        let manifest = backup(&archive)?;
+
+        fs::remove_file(Path::new(DB_PATH).join(".backup_whole_test.tmp"))?;
+
+        assert!(manifest.files.iter().any(|file| file.path == table));
+        assert!(
+            !manifest
+                .files
+                .iter()
+                .any(|file| file.path == ".backup_whole_test.tmp")
+        );
+
+        let staging = std::env::temp_dir().join("rust_bucket_backup_whole_test");
+
+        remove_dir(&staging)?;
+
+        assert_eq!(unpack(&archive, &staging)?, manifest);
+        assert!(staging.join(table).exists());
+
+        remove_dir(&staging)?;
+
+        fs::remove_file(&archive)?;
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn can_compress_a_backup() -> Result<()> {
+        let archive = std::env::temp_dir().join("rust_bucket_backup_compressed_test.tar.gz");
+        let staging = std::env::temp_dir().join("rust_bucket_backup_compressed_test");
+
+        let manifest = backup_compressed(&archive)?;
+
+        remove_dir(&staging)?;
+
+        assert_eq!(unpack(&archive, &staging)?, manifest);
+
+        remove_dir(&staging)?;
+
+        fs::remove_file(&archive)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Constraint module.
+//!
+//! Constraints are declared per table and kept in a hidden file next to the table file. They are
+//! checked against the whole table as it would be after a write, before anything is written, so
+//! a rejected write leaves the table untouched.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, Records, TableData, get_table, json_pointer, lock_writes, sidecar, table_exists,
+};
+
+pub(crate) const SIDECAR: &str = "constraints";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Constraints {
+    #[serde(default)]
+    unique: BTreeMap<String, String>,
+}
+
+/// Declares that no two records of `table` may hold the same value at `path`.
+///
+/// `path` is either a top-level field name or a JSON pointer. Records missing the value, or
+/// holding `null`, are exempt. Fails with `Error::UniqueViolation` if the records already stored
+/// break the constraint.
+pub fn add_unique_constraint(table: &str, name: &str, path: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut constraints = read_constraints(table)?;
+
+    let path = json_pointer(path);
+
+    check_unique(
+        table,
+        name,
+        &path,
+        &get_table::<Value>(table)?.into_live_records(),
+    )?;
+
+    constraints.unique.insert(name.to_string(), path);
+
+    write_constraints(table, &constraints)
+}
+
+/// Removes the unique constraint `name` from `table`.
+pub fn drop_unique_constraint(table: &str, name: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut constraints = read_constraints(table)?;
+
+    if constraints.unique.remove(name).is_none() {
+        return Err(Error::NoSuchConstraint(name.to_string()));
+    }
+
+    write_constraints(table, &constraints)
+}
+
+// Checks the table as a write would leave it against every constraint declared on it
+pub(crate) fn check<T: Serialize>(
+    table: &str,
+    data: &TableData<T>,
+    changes: &[Change],
+) -> Result<()> {
+    let inserts = changes
+        .iter()
+        .any(|change| matches!(change, Change::Insert { .. } | Change::Update { .. }));
+
+    if !inserts || !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let constraints = read_constraints(table)?;
+
+    if constraints.unique.is_empty() {
+        return Ok(());
+    }
+
+    // Expired records are gone, so their values are free to be taken again
+    let records = data
+        .records
+        .iter()
+        .filter(|&(id, _)| data.is_live(id))
+        .map(|(id, record)| Ok((id.clone(), serde_json::to_value(record)?)))
+        .collect::<Result<_>>()?;
+
+    for (name, path) in &constraints.unique {
+        check_unique(table, name, path, &records)?;
+    }
+
+    Ok(())
+}
+
+fn check_unique(table: &str, name: &str, path: &str, records: &Records<Value>) -> Result<()> {
+    let mut seen = HashMap::new();
+
+    for (id, record) in records {
+        let value = match record.pointer(path) {
+            None | Some(Value::Null) => continue,
+            Some(value) => value.to_string(),
+        };
+
+        if seen.insert(value.clone(), id).is_some() {
+            return Err(Error::UniqueViolation {
+                table: table.to_string(),
+                constraint: name.to_string(),
+                value,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn read_constraints(table: &str) -> Result<Constraints> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Ok(Constraints::default());
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_constraints(table: &str, constraints: &Constraints) -> Result<()> {
+    super::write_json(&sidecar(table, SIDECAR), constraints)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_constraints {
+    use super::*;
+    use crate::{
+        append_records, batch_insert, create_empty_table, drop_table, get_table_records,
+        update_record, update_where,
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct User {
+        pub email: Option<String>,
+    }
+
+    fn user(email: &str) -> User {
+        User {
+            email: Some(email.to_string()),
+        }
+    }
+
+    fn is_violation(result: Result<()>, email: &str) -> bool {
+        matches!(
+            result,
+            Err(Error::UniqueViolation { ref constraint, ref value, .. })
+                if constraint == "email" && *value == format!("\"{}\"", email)
+        )
+    }
+
+    #[test]
+    fn can_reject_duplicates_on_every_write() -> Result<()> {
+        let table = "unique_write_test";
+
+        create_empty_table::<User>(table)?;
+
+        add_unique_constraint(table, "email", "email")?;
+
+        append_records(table, user("a@x"))?;
+        append_records(table, User { email: None })?;
+        append_records(table, User { email: None })?;
+
+        assert!(is_violation(append_records(table, user("a@x")), "a@x"));
+
+        assert!(is_violation(
+            batch_insert(table, vec![user("b@x"), user("a@x")]),
+            "a@x"
+        ));
+
+        assert!(is_violation(
+            batch_insert(table, vec![user("c@x"), user("c@x")]),
+            "c@x"
+        ));
+
+        assert!(is_violation(update_record(table, "1", user("a@x")), "a@x"));
+
+        let updated = update_where::<User, _, _>(
+            table,
+            |u| u.email.is_none(),
+            |u| u.email = Some("a@x".to_string()),
+        );
+
+        assert!(is_violation(updated.map(|_| ()), "a@x"));
+
+        assert_eq!(get_table_records::<User>(table)?.len(), 3);
+
+        drop_unique_constraint(table, "email")?;
+
+        append_records(table, user("a@x"))?;
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_a_constraint_already_broken() -> Result<()> {
+        let table = "unique_existing_test";
+
+        create_empty_table::<User>(table)?;
+
+        batch_insert(table, vec![user("a@x"), user("a@x")])?;
+
+        assert!(is_violation(
+            add_unique_constraint(table, "email", "/email"),
+            "a@x"
+        ));
+
+        assert!(matches!(
+            drop_unique_constraint(table, "email"),
+            Err(Error::NoSuchConstraint(_))
+        ));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
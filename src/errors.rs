@@ -18,7 +18,11 @@ use std::result as std_result;
 use serde_json;
 
 // Bring the constructors of Error into scope so we can use them without an `Error::` incantation
-use self::Error::{Io, NoSuchKey, NoSuchTable, ParseInt, Serde};
+use self::Error::{
+    Conflict, CorruptArchive, Custom, InvalidInput, Io, Migration, NoSuchConstraint, NoSuchIndex,
+    NoSuchKey, NoSuchNamespace, NoSuchTable, ParseInt, ReferenceViolation, SelfReference, Serde,
+    TableExists, TransactionOpen, UniqueViolation, Validation,
+};
 
 /// A Result alias often returned from methods that can fail for `rust_bucket` exclusive reasons.
 pub type Result<T> = std_result::Result<T, Error>;
@@ -45,13 +49,126 @@ pub enum Error {
     /// The user tried to read a table, but no such table exists.
     NoSuchTable(String),
 
+    /// The user tried to use a namespace which was never created.
+    NoSuchNamespace(String),
+
+    /// The user tried to rename, copy or move a table onto one which already exists.
+    TableExists(String),
+
     /// The user tried to extract a key, but it didn't exist.
     NoSuchKey,
+
+    /// The user tried to use an index which was never created on the table.
+    NoSuchIndex(String),
+
+    /// The user tried to remove a constraint which was never declared on the table.
+    NoSuchConstraint(String),
+
+    /// A write would have left two records holding the same value under a unique constraint.
+    ///
+    /// `value` is the offending value, encoded as JSON. Nothing was written.
+    UniqueViolation {
+        table: String,
+        constraint: String,
+        value: String,
+    },
+
+    /// A write would have left a record of `table` referring to a missing record of
+    /// `referenced_table`, or deleted a record that `table` still refers to under
+    /// `OnDelete::Restrict`.
+    ///
+    /// `id` is the id of the record in `referenced_table`. Nothing was written.
+    ReferenceViolation {
+        table: String,
+        referenced_table: String,
+        id: String,
+    },
+
+    /// A reference from `table` to itself was declared with an `OnDelete` other than `Restrict`.
+    ///
+    /// `field` is the JSON pointer the reference was declared on. Nothing was declared.
+    SelfReference { table: String, field: String },
+
+    /// A write was made through the plain functions of this crate inside the closure handed to
+    /// `transaction`, where the commit of the transaction would write over it.
+    ///
+    /// Write through the `Transaction` instead. Nothing was written.
+    TransactionOpen,
+
+    /// A conditional update expected a record at a version it has since moved on from.
+    ///
+    /// `current_version` is the version the record is at now. Nothing was written.
+    Conflict { current_version: u64 },
+
+    /// Records written to `table` do not match its schema, or a schema being set is not one that
+    /// can be checked.
+    ///
+    /// Every problem found is listed. Nothing was written.
+    Validation {
+        table: String,
+        errors: Vec<ValidationError>,
+    },
+
+    /// The records of `table` could not be migrated from `version`, either because some of them
+    /// failed or, when `failures` is empty, because no migration is registered from `version`.
+    ///
+    /// `failures` holds the id of each failed record with the reason. Nothing was written.
+    Migration {
+        table: String,
+        version: u32,
+        failures: Vec<(String, String)>,
+    },
+
+    /// A backup archive handed to `restore` is damaged or not one `backup` wrote.
+    ///
+    /// Holds what was wrong with it. Nothing was restored.
+    CorruptArchive(String),
+
+    /// An argument handed to `rust_bucket` is out of range or malformed, such as a coordinate
+    /// that is not finite.
+    ///
+    /// Holds what was wrong with it. Nothing was written.
+    InvalidInput(String),
+
+    /// An error raised by code handed to `rust_bucket`, such as a hook rejecting a write.
+    Custom(Box<dyn std_error::Error + Send + Sync>),
+}
+
+/// One value that broke a schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// A JSON pointer to the value, starting with the id of its record, or into the schema.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, formatter: &mut Formatter) -> std_result::Result<(), fmt::Error> {
+        write!(formatter, "{}: {}", self.path, self.message)
+    }
+}
+
+impl Error {
+    /// Wraps an error of your own, or just a message, for returning from a hook.
+    pub fn custom<E>(err: E) -> Error
+    where
+        E: Into<Box<dyn std_error::Error + Send + Sync>>,
+    {
+        Custom(err.into())
+    }
 }
 
 impl From<io::Error> for Error {
+    // Functions which return `io::Result` carry any other error inside the `io::Error`, so it is
+    // unwrapped again here
     fn from(err: io::Error) -> Error {
-        Io(err)
+        if !err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return Io(err);
+        }
+
+        *err.into_inner()
+            .and_then(|inner| inner.downcast().ok())
+            .expect("the inner error is an Error")
     }
 }
 
@@ -89,7 +206,123 @@ impl Display for Error {
                     table,
                 )
             }
+            NoSuchNamespace(ref namespace) => {
+                write!(
+                    formatter,
+                    "Tried to use the namespace \"{}\", which does not exist.",
+                    namespace,
+                )
+            }
+            TableExists(ref table) => {
+                write!(
+                    formatter,
+                    "Tried to write over the table \"{}\", which already exists.",
+                    table,
+                )
+            }
             NoSuchKey => write!(formatter, "Tried to retrieve a key which doesn't exist."),
+            NoSuchIndex(ref index) => {
+                write!(
+                    formatter,
+                    "Tried to use the index \"{}\", which does not exist.",
+                    index,
+                )
+            }
+            NoSuchConstraint(ref constraint) => {
+                write!(
+                    formatter,
+                    "Tried to remove the constraint \"{}\", which does not exist.",
+                    constraint,
+                )
+            }
+            UniqueViolation {
+                ref table,
+                ref constraint,
+                ref value,
+            } => {
+                write!(
+                    formatter,
+                    "The value {} appears more than once in the table \"{}\", \
+                     breaking the unique constraint \"{}\".",
+                    value, table, constraint,
+                )
+            }
+            ReferenceViolation {
+                ref table,
+                ref referenced_table,
+                ref id,
+            } => {
+                write!(
+                    formatter,
+                    "A record of the table \"{}\" refers to the record \"{}\" of the table \"{}\", \
+                     which does not exist or may not be deleted.",
+                    table, id, referenced_table,
+                )
+            }
+            SelfReference {
+                ref table,
+                ref field,
+            } => {
+                write!(
+                    formatter,
+                    "The field \"{}\" of the table \"{}\" refers to the same table, \
+                     which is only allowed with OnDelete::Restrict.",
+                    field, table,
+                )
+            }
+            TransactionOpen => write!(
+                formatter,
+                "Tried to write around a transaction from inside it, instead of through it."
+            ),
+            Conflict { current_version } => {
+                write!(
+                    formatter,
+                    "The record was updated by someone else and is now at version {}.",
+                    current_version,
+                )
+            }
+            Validation {
+                ref table,
+                ref errors,
+            } => {
+                write!(formatter, "Validation failed for the table \"{}\":", table)?;
+
+                for error in errors {
+                    write!(formatter, " {};", error)?;
+                }
+
+                Ok(())
+            }
+            Migration {
+                ref table,
+                version,
+                ref failures,
+            } => {
+                if failures.is_empty() {
+                    return write!(
+                        formatter,
+                        "No migration is registered to take the table \"{}\" past version {}.",
+                        table, version,
+                    );
+                }
+
+                write!(
+                    formatter,
+                    "Migrating the table \"{}\" from version {} failed:",
+                    table, version,
+                )?;
+
+                for (id, reason) in failures {
+                    write!(formatter, " {}: {};", id, reason)?;
+                }
+
+                Ok(())
+            }
+            CorruptArchive(ref reason) => {
+                write!(formatter, "The backup archive is corrupt: {}.", reason)
+            }
+            InvalidInput(ref reason) => write!(formatter, "Invalid input: {}.", reason),
+            Custom(ref err) => err.fmt(formatter),
         }
     }
 }
@@ -103,7 +336,21 @@ impl std_error::Error for Error {
             Serde(ref err) => Some(err),
             ParseInt(ref err) => Some(err),
             NoSuchTable(_) => None,
+            NoSuchNamespace(_) => None,
+            TableExists(_) => None,
             NoSuchKey => None,
+            NoSuchIndex(_) => None,
+            NoSuchConstraint(_) => None,
+            UniqueViolation { .. } => None,
+            ReferenceViolation { .. } => None,
+            SelfReference { .. } => None,
+            TransactionOpen => None,
+            Conflict { .. } => None,
+            Validation { .. } => None,
+            Migration { .. } => None,
+            CorruptArchive(_) => None,
+            InvalidInput(_) => None,
+            Custom(ref err) => Some(&**err),
         }
     }
 }
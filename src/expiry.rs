@@ -0,0 +1,350 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Expiry module.
+//!
+//! Records can be given a time to live. Once it runs out they are gone as far as every read,
+//! write and constraint is concerned: `find` and `update_record` fail with `Error::NoSuchKey`,
+//! `delete_if_exists` finds nothing, unique constraints and searches skip them, and neither the
+//! streaming functions nor transactions see them. They stay on disk until `purge_expired` deletes
+//! them, either when called directly or by a `Sweeper`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, commit_table, get_table, list_namespaces, list_tables, list_tables_in, lock_writes,
+    read_table, stream,
+};
+
+/// Deletes expired records in the background for as long as it is kept alive.
+///
+/// Built by `sweep_expired`. Dropping it stops the sweeping and waits for the sweep in progress,
+/// if any, to finish.
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Appends a record that expires once `ttl` has passed.
+pub fn append_records_with_ttl<T>(table: &str, t: T, ttl: Duration) -> Result<()>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table(table)?;
+
+    let next_id = data.next_id.parse::<i32>()?;
+
+    let id = next_id.to_string();
+
+    let mut changes = [Change::insert(&id, &t)?];
+
+    data.expires.insert(id.clone(), deadline(ttl));
+
+    data.records.insert(id, t);
+
+    data.next_id = (next_id + 1).to_string();
+
+    commit_table(table, &mut data, &mut changes)
+}
+
+/// Gives an existing record a new time to live, or with `None` lets it live forever.
+pub fn set_ttl(table: &str, id: &str, ttl: Option<Duration>) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<Value>(table)?;
+
+    if !data.is_live(id) {
+        return Err(Error::NoSuchKey);
+    }
+
+    match ttl {
+        Some(ttl) => data.expires.insert(id.to_string(), deadline(ttl)),
+        None => data.expires.remove(id),
+    };
+
+    super::upgrade_table(table, &data)
+}
+
+/// Deletes the records of `table` whose time to live has run out, returning their ids.
+///
+/// The records are deleted as by `delete_where`, so indexes and references see them go.
+pub fn purge_expired(table: &str) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Expiries {
+        #[serde(default)]
+        expires: BTreeMap<String, u64>,
+    }
+
+    // Most tables hold nothing that expires, so check before paying for the write lock
+    let expiries: Expiries = serde_json::from_str(&read_table(table)?)?;
+
+    let now = now();
+
+    if !expiries
+        .expires
+        .values()
+        .any(|expires| has_expired(Some(expires), now))
+    {
+        return Ok(Vec::new());
+    }
+
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<Value>(table)?;
+
+    let ids: Vec<String> = data
+        .expires
+        .iter()
+        .filter(|&(_, expires)| has_expired(Some(expires), now))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    let mut changes = Vec::with_capacity(ids.len());
+
+    for id in &ids {
+        match data.records.remove(id) {
+            Some(old) => changes.push(Change::Delete {
+                id: id.clone(),
+                old,
+            }),
+            None => {
+                data.expires.remove(id);
+            }
+        }
+    }
+
+    commit_table(table, &mut data, &mut changes)?;
+
+    Ok(ids)
+}
+
+/// Starts a thread that calls `purge_expired` on every table, in every namespace, once per
+/// `interval`.
+///
+/// Tables that fail to purge, for instance because they were dropped meanwhile, are skipped
+/// until the next sweep.
+pub fn sweep_expired(interval: Duration) -> Sweeper {
+    let (stop, stopped) = mpsc::channel::<()>();
+
+    let handle = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            let mut tables = list_tables().unwrap_or_default();
+
+            for namespace in list_namespaces().unwrap_or_default() {
+                tables.extend(list_tables_in(&namespace).unwrap_or_default());
+            }
+
+            for table in tables {
+                let _ = purge_expired(&table);
+            }
+        }
+    });
+
+    Sweeper {
+        stop: Some(stop),
+        handle: Some(handle),
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // Hanging up the channel is what tells the thread to stop
+        drop(self.stop.take());
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// The current time in milliseconds since the Unix epoch, the unit expiry times are kept in
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Whether a record with the given expiry time, if it has one, is gone by `now`. Everything that
+// reads, writes or checks records asks this, so they all agree on when a record expires.
+pub(crate) fn has_expired(expires: Option<&u64>, now: u64) -> bool {
+    expires.is_some_and(|&expires| expires <= now)
+}
+
+// The ids of the records of a table which have expired, found without parsing any record
+pub(crate) fn expired_ids(table: &str) -> Result<BTreeSet<String>> {
+    let now = now();
+
+    Ok(stream::read_expires(table)?
+        .into_iter()
+        .filter(|(_, expires)| has_expired(Some(expires), now))
+        .map(|(id, _)| id)
+        .collect())
+}
+
+fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_expiry {
+    use super::*;
+    use crate::{
+        add_unique_constraint, append_records, count_records, create_empty_table,
+        create_search_index, delete, delete_if_exists, delete_where, drop_table, find, find_by,
+        get_table_records, iter_records, search, stream_count_records, stream_find_by, transaction,
+        update_record, update_where,
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Session {
+        pub user: String,
+    }
+
+    fn session(user: &str) -> Session {
+        Session {
+            user: user.to_string(),
+        }
+    }
+
+    #[test]
+    fn can_hide_and_purge_expired_records() -> Result<()> {
+        let table = "expiry_purge_test";
+
+        create_empty_table::<Session>(table)?;
+
+        append_records_with_ttl(table, session("gone"), Duration::ZERO)?;
+        append_records_with_ttl(table, session("kept"), Duration::from_secs(3600))?;
+        append_records(table, session("forever"))?;
+
+        assert!(matches!(find::<Session>(table, "0"), Err(Error::NoSuchKey)));
+        assert_eq!(find::<Session>(table, "1")?, session("kept"));
+
+        assert_eq!(count_records::<Session>(table)?, 2);
+        assert_eq!(get_table_records::<Session>(table)?.len(), 2);
+        assert!(find_by::<Session, _>(table, |s| s.user == "gone")?.is_empty());
+
+        set_ttl(table, "2", Some(Duration::ZERO))?;
+        set_ttl(table, "1", None)?;
+
+        assert!(matches!(set_ttl(table, "7", None), Err(Error::NoSuchKey)));
+
+        assert_eq!(purge_expired(table)?, vec!["0", "2"]);
+        assert!(purge_expired(table)?.is_empty());
+
+        let data = get_table::<Session>(table)?;
+
+        assert_eq!(data.records.keys().collect::<Vec<_>>(), vec!["1"]);
+        assert!(data.expires.is_empty());
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_treat_expired_records_as_gone() -> Result<()> {
+        let table = "expiry_gone_test";
+
+        create_empty_table::<Session>(table)?;
+
+        add_unique_constraint(table, "user", "user")?;
+        create_search_index(table, &["user"])?;
+
+        append_records_with_ttl(table, session("gone"), Duration::ZERO)?;
+
+        assert!(matches!(
+            update_record(table, "0", session("back")),
+            Err(Error::NoSuchKey)
+        ));
+        assert!(matches!(
+            delete::<Session>(table, "0"),
+            Err(Error::NoSuchKey)
+        ));
+        assert!(delete_if_exists::<Session>(table, "0")?.is_none());
+        assert!(update_where::<Session, _, _>(table, |_| true, |_| {})?.is_empty());
+        assert!(delete_where::<Session, _>(table, |_| true)?.is_empty());
+        assert!(matches!(set_ttl(table, "0", None), Err(Error::NoSuchKey)));
+
+        append_records(table, session("gone"))?;
+
+        assert_eq!(search(table, "gone")?.len(), 1);
+        assert_eq!(search(table, "gone")?[0].0, "1");
+
+        assert_eq!(
+            stream_count_records(table)?,
+            count_records::<Session>(table)?
+        );
+        assert_eq!(iter_records::<Session>(table)?.count(), 1);
+        assert_eq!(stream_find_by::<Session, _>(table, |_| true)?.len(), 1);
+
+        transaction(|tx| {
+            assert!(matches!(
+                tx.find::<Session>(table, "0"),
+                Err(Error::NoSuchKey)
+            ));
+            assert!(matches!(
+                tx.update_record(table, "0", session("back")),
+                Err(Error::NoSuchKey)
+            ));
+            assert!(matches!(
+                tx.delete::<Session>(table, "0"),
+                Err(Error::NoSuchKey)
+            ));
+            assert_eq!(tx.get_table_records::<Session>(table)?.len(), 1);
+
+            Ok(())
+        })?;
+
+        assert_eq!(purge_expired(table)?, vec!["0"]);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_sweep_in_the_background() -> Result<()> {
+        let table = "expiry_sweep_test";
+
+        create_empty_table::<Session>(table)?;
+
+        append_records_with_ttl(table, session("gone"), Duration::ZERO)?;
+
+        let sweeper = sweep_expired(Duration::from_millis(5));
+
+        for _ in 0..400 {
+            if get_table::<Session>(table)?.records.is_empty() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        drop(sweeper);
+
+        assert!(get_table::<Session>(table)?.records.is_empty());
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
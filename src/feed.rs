@@ -0,0 +1,247 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Change feed module.
+//!
+//! Callbacks subscribed to a table, or to every table, are handed an event for each record a
+//! write changed, once the write has reached the disk. They run on the writing thread while it
+//! still holds the write lock, so they see changes in the order they were made and should be
+//! quick. A callback may write to the database itself.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde_json::Value;
+
+use super::Change;
+
+/// Something that happened to a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub table: String,
+    pub kind: EventKind,
+}
+
+/// What happened, with the records involved as JSON.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    Insert {
+        id: String,
+        new: Value,
+    },
+    Update {
+        id: String,
+        old: Value,
+        new: Value,
+    },
+    Delete {
+        id: String,
+        old: Value,
+    },
+    /// Every record was removed at once, or the table was overwritten wholesale.
+    Clear,
+    Drop,
+}
+
+/// Keeps a callback subscribed for as long as it is alive.
+///
+/// Built by `subscribe`. Dropping it unsubscribes the callback.
+pub struct Subscription {
+    id: u64,
+}
+
+type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+struct Subscriber {
+    id: u64,
+    table: Option<String>,
+    callback: Callback,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Calls `callback` with every change made to `table`, or to every table if it is `None`.
+pub fn subscribe<F>(table: Option<&str>, callback: F) -> Subscription
+where
+    F: Fn(&Event) + Send + Sync + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    lock_subscribers().push(Subscriber {
+        id,
+        table: table.map(str::to_string),
+        callback: Arc::new(callback),
+    });
+
+    Subscription { id }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock_subscribers().retain(|subscriber| subscriber.id != self.id);
+    }
+}
+
+// Hands the changes of a committed write to everyone subscribed to the table
+pub(crate) fn publish(table: &str, changes: &[Change]) {
+    let callbacks = callbacks(table);
+
+    if callbacks.is_empty() {
+        return;
+    }
+
+    for change in changes {
+        let kind = match change {
+            Change::Insert { id, new } => EventKind::Insert {
+                id: id.clone(),
+                new: new.clone(),
+            },
+            Change::Update { id, old, new } => EventKind::Update {
+                id: id.clone(),
+                old: old.clone(),
+                new: new.clone(),
+            },
+            Change::Delete { id, old } => EventKind::Delete {
+                id: id.clone(),
+                old: old.clone(),
+            },
+            Change::Clear => EventKind::Clear,
+        };
+
+        deliver(&callbacks, table, kind);
+    }
+}
+
+pub(crate) fn publish_drop(table: &str) {
+    deliver(&callbacks(table), table, EventKind::Drop);
+}
+
+fn deliver(callbacks: &[Callback], table: &str, kind: EventKind) {
+    let event = Event {
+        table: table.to_string(),
+        kind,
+    };
+
+    for callback in callbacks {
+        callback(&event);
+    }
+}
+
+// Copies the callbacks out, so they are free to subscribe and unsubscribe while being called
+fn callbacks(table: &str) -> Vec<Callback> {
+    lock_subscribers()
+        .iter()
+        .filter(|subscriber| subscriber.table.as_deref().is_none_or(|t| t == table))
+        .map(|subscriber| subscriber.callback.clone())
+        .collect()
+}
+
+fn lock_subscribers() -> MutexGuard<'static, Vec<Subscriber>> {
+    SUBSCRIBERS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_feed {
+    use super::*;
+    use crate::errors::Result;
+    use crate::{
+        append_records, clear_table, create_empty_table, delete, drop_table, update_record,
+    };
+    use serde_json::json;
+
+    fn record(events: &Arc<Mutex<Vec<Event>>>) -> impl Fn(&Event) + Send + Sync + 'static {
+        let events = events.clone();
+
+        move |event| events.lock().unwrap().push(event.clone())
+    }
+
+    #[test]
+    fn can_deliver_every_kind_of_change() -> Result<()> {
+        let table = "feed_kinds_test";
+
+        create_empty_table::<Value>(table)?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let everything = Arc::new(Mutex::new(Vec::new()));
+
+        let subscription = subscribe(Some(table), record(&events));
+        let all = subscribe(None, record(&everything));
+
+        append_records(table, json!({"n": 1}))?;
+        update_record(table, "0", json!({"n": 2}))?;
+        delete::<Value>(table, "0")?;
+        clear_table::<Value>(table)?;
+        drop_table(table)?;
+
+        let kinds: Vec<EventKind> = events.lock().unwrap().drain(..).map(|e| e.kind).collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Insert {
+                    id: "0".to_string(),
+                    new: json!({"n": 1}),
+                },
+                EventKind::Update {
+                    id: "0".to_string(),
+                    old: json!({"n": 1}),
+                    new: json!({"n": 2}),
+                },
+                EventKind::Delete {
+                    id: "0".to_string(),
+                    old: json!({"n": 2}),
+                },
+                EventKind::Clear,
+                EventKind::Drop,
+            ]
+        );
+
+        let mine = everything
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.table == table)
+            .count();
+
+        assert_eq!(mine, 5);
+
+        drop(subscription);
+        drop(all);
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_unsubscribe_and_skip_failed_writes() -> Result<()> {
+        let table = "feed_unsubscribe_test";
+
+        create_empty_table::<Value>(table)?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let subscription = subscribe(Some(table), record(&events));
+
+        assert!(update_record(table, "9", json!({})).is_err());
+
+        append_records(table, json!(1))?;
+
+        drop(subscription);
+
+        append_records(table, json!(2))?;
+
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
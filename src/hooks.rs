@@ -0,0 +1,337 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Hooks module.
+//!
+//! A table can be given hooks that see every record inserted, updated or deleted in it, whether
+//! one at a time or in a batch. Before hooks run ahead of constraints and may rewrite the record
+//! being written or reject the whole write by returning an error, in which case nothing is
+//! written. After hooks run once the write is on disk. Overwriting a table with `update_table`
+//! inserts its one record as any other write does, so it runs the insert hooks, but the records it
+//! replaces are not seen to go. Clearing a table, writing raw JSON over it and dropping it run no
+//! hooks.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::Result;
+use super::{Change, TableData};
+
+type BeforeInsert = Box<dyn Fn(&str, &mut Value) -> Result<()> + Send + Sync>;
+type BeforeUpdate = Box<dyn Fn(&str, &Value, &mut Value) -> Result<()> + Send + Sync>;
+type BeforeDelete = Box<dyn Fn(&str, &Value) -> Result<()> + Send + Sync>;
+type AfterWrite = Box<dyn Fn(&str, &Value) + Send + Sync>;
+type AfterUpdate = Box<dyn Fn(&str, &Value, &Value) + Send + Sync>;
+
+/// The hooks of one table, each kind run in the order it was added.
+///
+/// Hooks are handed the id of the record and the record itself as JSON. Update hooks get the
+/// old record before the new one.
+#[derive(Default)]
+pub struct Hooks {
+    before_insert: Vec<BeforeInsert>,
+    before_update: Vec<BeforeUpdate>,
+    before_delete: Vec<BeforeDelete>,
+    after_insert: Vec<AfterWrite>,
+    after_update: Vec<AfterUpdate>,
+    after_delete: Vec<AfterWrite>,
+}
+
+static HOOKS: Mutex<BTreeMap<String, Arc<Hooks>>> = Mutex::new(BTreeMap::new());
+
+/// Installs `hooks` on `table`, replacing any it had.
+pub fn set_hooks(table: &str, hooks: Hooks) {
+    lock_hooks().insert(table.to_string(), Arc::new(hooks));
+}
+
+/// Removes the hooks of `table`, returning whether it had any.
+pub fn remove_hooks(table: &str) -> bool {
+    lock_hooks().remove(table).is_some()
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    pub fn before_insert<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &mut Value) -> Result<()> + Send + Sync + 'static,
+    {
+        self.before_insert.push(Box::new(hook));
+        self
+    }
+
+    pub fn before_update<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &Value, &mut Value) -> Result<()> + Send + Sync + 'static,
+    {
+        self.before_update.push(Box::new(hook));
+        self
+    }
+
+    pub fn before_delete<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &Value) -> Result<()> + Send + Sync + 'static,
+    {
+        self.before_delete.push(Box::new(hook));
+        self
+    }
+
+    pub fn after_insert<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &Value) + Send + Sync + 'static,
+    {
+        self.after_insert.push(Box::new(hook));
+        self
+    }
+
+    pub fn after_update<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &Value, &Value) + Send + Sync + 'static,
+    {
+        self.after_update.push(Box::new(hook));
+        self
+    }
+
+    pub fn after_delete<F>(mut self, hook: F) -> Hooks
+    where
+        F: Fn(&str, &Value) + Send + Sync + 'static,
+    {
+        self.after_delete.push(Box::new(hook));
+        self
+    }
+}
+
+// Runs the before hooks over a write about to be committed, copying whatever they rewrote back
+// into the table
+pub(crate) fn before<T>(table: &str, data: &mut TableData<T>, changes: &mut [Change]) -> Result<()>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let hooks = match hooks_for(table) {
+        Some(hooks) => hooks,
+        None => return Ok(()),
+    };
+
+    for change in changes {
+        let (id, new) = match change {
+            Change::Insert { id, new } => {
+                for hook in &hooks.before_insert {
+                    hook(id, new)?;
+                }
+
+                (id, new)
+            }
+            Change::Update { id, old, new } => {
+                for hook in &hooks.before_update {
+                    hook(id, old, new)?;
+                }
+
+                (id, new)
+            }
+            Change::Delete { id, old } => {
+                for hook in &hooks.before_delete {
+                    hook(id, old)?;
+                }
+
+                continue;
+            }
+            Change::Clear => continue,
+        };
+
+        // Going through the text rather than the value keeps raw records working
+        let record = serde_json::from_str(&serde_json::to_string(new)?)?;
+
+        data.records.insert(id.clone(), record);
+    }
+
+    Ok(())
+}
+
+// Runs the after hooks over a committed write
+pub(crate) fn after(table: &str, changes: &[Change]) {
+    let hooks = match hooks_for(table) {
+        Some(hooks) => hooks,
+        None => return,
+    };
+
+    for change in changes {
+        match change {
+            Change::Insert { id, new } => {
+                for hook in &hooks.after_insert {
+                    hook(id, new);
+                }
+            }
+            Change::Update { id, old, new } => {
+                for hook in &hooks.after_update {
+                    hook(id, old, new);
+                }
+            }
+            Change::Delete { id, old } => {
+                for hook in &hooks.after_delete {
+                    hook(id, old);
+                }
+            }
+            Change::Clear => {}
+        }
+    }
+}
+
+// Hooks are run outside the lock, so they may install or remove hooks themselves
+fn hooks_for(table: &str) -> Option<Arc<Hooks>> {
+    lock_hooks().get(table).cloned()
+}
+
+fn lock_hooks() -> MutexGuard<'static, BTreeMap<String, Arc<Hooks>>> {
+    HOOKS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_hooks {
+    use super::*;
+    use crate::errors::Error;
+    use crate::{
+        append_records, batch_insert, count_records, create_empty_table, delete, drop_table, find,
+        update_record, update_table,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct User {
+        pub email: String,
+        #[serde(default)]
+        pub edits: u32,
+    }
+
+    fn user(email: &str) -> User {
+        User {
+            email: email.to_string(),
+            edits: 0,
+        }
+    }
+
+    fn lowercase_email(_: &str, record: &mut Value) -> Result<()> {
+        let email = record["email"].as_str().unwrap_or_default().to_lowercase();
+
+        if email.is_empty() {
+            return Err(Error::custom("a user needs an email"));
+        }
+
+        record["email"] = Value::from(email);
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_rewrite_and_reject_writes() -> Result<()> {
+        let table = "hooks_rewrite_test";
+
+        create_empty_table::<User>(table)?;
+
+        set_hooks(
+            table,
+            Hooks::new()
+                .before_insert(lowercase_email)
+                .before_update(|id, old, new| {
+                    lowercase_email(id, new)?;
+
+                    new["edits"] = Value::from(old["edits"].as_u64().unwrap_or(0) + 1);
+
+                    Ok(())
+                })
+                .before_delete(|id, _| match id {
+                    "0" => Err(Error::custom("the first user stays")),
+                    _ => Ok(()),
+                }),
+        );
+
+        append_records(table, user("Ada@Example.com"))?;
+        batch_insert(
+            table,
+            vec![user("BOB@example.com"), user("eve@example.com")],
+        )?;
+
+        assert_eq!(find::<User>(table, "1")?, user("bob@example.com"));
+
+        update_record(table, "0", user("ADA@example.com"))?;
+
+        assert_eq!(
+            find::<User>(table, "0")?,
+            User {
+                email: "ada@example.com".to_string(),
+                edits: 1,
+            }
+        );
+
+        let result = batch_insert(table, vec![user("ok@example.com"), user("")]);
+
+        assert!(matches!(result, Err(Error::Custom(_))));
+        assert_eq!(count_records::<User>(table)?, 3);
+
+        assert!(matches!(delete::<User>(table, "0"), Err(Error::Custom(_))));
+        assert_eq!(delete::<User>(table, "2")?, user("eve@example.com"));
+
+        update_table(table, &user("Zed@Example.com"))?;
+
+        assert_eq!(find::<User>(table, "0")?, user("zed@example.com"));
+
+        assert!(remove_hooks(table));
+        assert!(!remove_hooks(table));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_run_after_a_commit() -> Result<()> {
+        let table = "hooks_after_test";
+
+        create_empty_table::<User>(table)?;
+
+        let writes = Arc::new(AtomicUsize::new(0));
+
+        let (inserts, updates, deletes) = (writes.clone(), writes.clone(), writes.clone());
+
+        set_hooks(
+            table,
+            Hooks::new()
+                .after_insert(move |_, _| {
+                    inserts.fetch_add(1, Ordering::SeqCst);
+                })
+                .after_update(move |_, _, _| {
+                    updates.fetch_add(10, Ordering::SeqCst);
+                })
+                .after_delete(move |_, _| {
+                    deletes.fetch_add(100, Ordering::SeqCst);
+                }),
+        );
+
+        batch_insert(table, vec![user("a@example.com"), user("b@example.com")])?;
+        update_record(table, "1", user("c@example.com"))?;
+        delete::<User>(table, "0")?;
+
+        assert!(update_record(table, "7", user("d@example.com")).is_err());
+
+        assert_eq!(writes.load(Ordering::SeqCst), 112);
+
+        remove_hooks(table);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
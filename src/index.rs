@@ -0,0 +1,322 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Secondary index module.
+//!
+//! An index maps the value found at one path of every record to the ids of the records holding
+//! it. The indexes of a table live together in a hidden file next to the table file, and every
+//! write through this crate keeps them up to date.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, Records, find_many, get_table, json_pointer, lock_writes, sidecar, stream, table_exists,
+};
+
+pub(crate) const SIDECAR: &str = "indexes";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+    path: String,
+    entries: BTreeMap<String, Vec<String>>,
+}
+
+impl Index {
+    fn add(&mut self, id: &str, record: &Value) {
+        if let Some(key) = self.key(record) {
+            self.entries.entry(key).or_default().push(id.to_string());
+        }
+    }
+
+    fn remove(&mut self, id: &str, record: &Value) {
+        if let Some(key) = self.key(record)
+            && let Some(ids) = self.entries.get_mut(&key)
+        {
+            ids.retain(|indexed| indexed != id);
+
+            if ids.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    // Missing and null values are left out of the index
+    fn key(&self, record: &Value) -> Option<String> {
+        match record.pointer(&self.path) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(value.to_string()),
+        }
+    }
+}
+
+type Indexes = BTreeMap<String, Index>;
+
+/// Creates the index `name` over the value each record of `table` holds at `path`, and builds it
+/// from the records already stored.
+///
+/// `path` is either a top-level field name such as `"email"` or a JSON pointer such as
+/// `"/address/city"`. Creating an index which already exists rebuilds it.
+pub fn create_index(table: &str, name: &str, path: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut indexes = read_indexes(table)?;
+
+    let mut index = Index {
+        path: json_pointer(path),
+        entries: BTreeMap::new(),
+    };
+
+    for (id, record) in &get_table::<Value>(table)?.records {
+        index.add(id, record);
+    }
+
+    indexes.insert(name.to_string(), index);
+
+    write_indexes(table, &indexes)
+}
+
+/// Rebuilds the index `name` of `table` from scratch.
+pub fn rebuild_index(table: &str, name: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let path = match read_indexes(table)?.get(name) {
+        Some(index) => index.path.clone(),
+        None => return Err(Error::NoSuchIndex(name.to_string())),
+    };
+
+    create_index(table, name, &path)
+}
+
+/// Removes the index `name` of `table`.
+pub fn drop_index(table: &str, name: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut indexes = read_indexes(table)?;
+
+    if indexes.remove(name).is_none() {
+        return Err(Error::NoSuchIndex(name.to_string()));
+    }
+
+    if indexes.is_empty() {
+        fs::remove_file(sidecar(table, SIDECAR))?;
+
+        return Ok(());
+    }
+
+    write_indexes(table, &indexes)
+}
+
+/// Returns every record of `table` whose indexed value equals `value`.
+///
+/// The index file is streamed only as far as the entry for `value`, as entries are kept in
+/// order, and of the table only the records that entry names are parsed.
+pub fn find_by_index<T, V>(table: &str, name: &str, value: &V) -> Result<Records<T>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    V: Serialize,
+{
+    let key = serde_json::to_value(value)?.to_string();
+
+    let ids = read_entry(table, name, &key)?;
+
+    Ok(find_many(table, &ids)?.into_iter().collect())
+}
+
+// Brings the indexes of a table in line with a write that has just been committed
+pub(crate) fn apply(table: &str, changes: &[Change]) -> Result<()> {
+    if changes.is_empty() || !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let mut indexes = read_indexes(table)?;
+
+    for index in indexes.values_mut() {
+        for change in changes {
+            match *change {
+                Change::Insert { ref id, ref new } => index.add(id, new),
+                Change::Update {
+                    ref id,
+                    ref old,
+                    ref new,
+                } => {
+                    index.remove(id, old);
+                    index.add(id, new);
+                }
+                Change::Delete { ref id, ref old } => index.remove(id, old),
+                Change::Clear => index.entries.clear(),
+            }
+        }
+    }
+
+    write_indexes(table, &indexes)
+}
+
+// The ids one index files under `key`, stepping over every other index and key without parsing
+fn read_entry(table: &str, name: &str, key: &str) -> Result<Vec<String>> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Err(Error::NoSuchIndex(name.to_string()));
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    match stream::find_entry(file, name, key)? {
+        Some(ids) => serde_json::from_slice(&ids).map_err(Error::from),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn read_indexes(table: &str) -> Result<Indexes> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Ok(Indexes::new());
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_indexes(table: &str, indexes: &Indexes) -> Result<()> {
+    super::write_json(&sidecar(table, SIDECAR), indexes)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_index {
+    use super::*;
+    use crate::{
+        append_records, batch_insert, clear_table, create_empty_table, delete, drop_table,
+        update_record,
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct User {
+        pub email: String,
+        pub address: Address,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Address {
+        pub city: String,
+    }
+
+    fn user(email: &str, city: &str) -> User {
+        User {
+            email: email.to_string(),
+            address: Address {
+                city: city.to_string(),
+            },
+        }
+    }
+
+    fn ids(records: Records<User>) -> Vec<String> {
+        records.keys().cloned().collect()
+    }
+
+    #[test]
+    fn can_find_by_field_and_pointer() -> Result<()> {
+        let table = "index_find_test";
+
+        create_empty_table::<User>(table)?;
+
+        batch_insert(
+            table,
+            vec![
+                user("a@x", "Paris"),
+                user("b@x", "Oslo"),
+                user("c@x", "Paris"),
+            ],
+        )?;
+
+        create_index(table, "email", "email")?;
+        create_index(table, "city", "/address/city")?;
+
+        let found = find_by_index::<User, _>(table, "email", &"b@x")?;
+
+        assert_eq!(found.get("1"), Some(&user("b@x", "Oslo")));
+
+        assert_eq!(ids(find_by_index(table, "city", &"Paris")?), vec!["0", "2"]);
+
+        assert!(find_by_index::<User, _>(table, "city", &"Rome")?.is_empty());
+        assert!(find_by_index::<User, _>(table, "city", &"Bern")?.is_empty());
+
+        assert!(matches!(
+            find_by_index::<User, _>(table, "name", &"a"),
+            Err(Error::NoSuchIndex(_))
+        ));
+
+        drop_table(table)?;
+
+        assert!(!sidecar(table, SIDECAR).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_keep_indexes_up_to_date() -> Result<()> {
+        let table = "index_write_test";
+
+        create_empty_table::<User>(table)?;
+
+        create_index(table, "city", "/address/city")?;
+
+        append_records(table, user("a@x", "Paris"))?;
+        batch_insert(table, vec![user("b@x", "Oslo"), user("c@x", "Paris")])?;
+
+        assert_eq!(ids(find_by_index(table, "city", &"Paris")?), vec!["0", "2"]);
+
+        update_record(table, "0", user("a@x", "Oslo"))?;
+
+        assert_eq!(ids(find_by_index(table, "city", &"Oslo")?), vec!["0", "1"]);
+
+        delete::<User>(table, "1")?;
+
+        assert_eq!(ids(find_by_index(table, "city", &"Oslo")?), vec!["0"]);
+
+        clear_table::<User>(table)?;
+
+        assert!(find_by_index::<User, _>(table, "city", &"Paris")?.is_empty());
+
+        append_records(table, user("d@x", "Rome"))?;
+
+        rebuild_index(table, "city")?;
+
+        assert_eq!(ids(find_by_index(table, "city", &"Rome")?), vec!["0"]);
+
+        drop_index(table, "city")?;
+
+        assert!(matches!(
+            find_by_index::<User, _>(table, "city", &"Rome"),
+            Err(Error::NoSuchIndex(_))
+        ));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
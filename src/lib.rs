@@ -12,18 +12,85 @@ extern crate serde_json;
 
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
+use serde::de::IgnoredAny;
+use serde_json::Value;
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::BufWriter;
 use std::io::prelude::*;
+use std::ops::Bound;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
 
 pub mod errors;
 use errors::{Error, Result};
 
+pub mod backup;
+#[cfg(feature = "compression")]
+pub use backup::backup_compressed;
+pub use backup::{Manifest, ManifestEntry, RestoreMode, backup, restore};
+
+pub mod expiry;
+pub use expiry::{Sweeper, append_records_with_ttl, purge_expired, set_ttl, sweep_expired};
+
+pub mod aggregate;
+pub use aggregate::{
+    Aggregate, Extract, Field, FieldValue, Grouped, Number, Sum, aggregate, field,
+};
+
+pub mod constraints;
+pub use constraints::{add_unique_constraint, drop_unique_constraint};
+
+pub mod feed;
+pub use feed::{Event, EventKind, Subscription, subscribe};
+
+pub mod hooks;
+pub use hooks::{Hooks, remove_hooks, set_hooks};
+
+pub mod index;
+pub use index::{create_index, drop_index, find_by_index, rebuild_index};
+
+pub mod meta;
+pub use meta::{TableInfo, list_tables_detailed, remove_tag, set_tag, table_info};
+
+pub mod migration;
+pub use migration::{MigrationReport, migrate_table, register_migration, register_typed_migration};
+
+pub mod namespace;
+pub use namespace::{create_namespace, drop_namespace, list_namespaces, list_tables_in, qualified};
+
+pub mod references;
+pub use references::{OnDelete, add_reference, drop_reference};
+
+pub mod schema;
+pub use schema::{drop_schema, get_schema, set_schema};
+
+pub mod search;
+pub use search::{create_search_index, drop_search_index, search};
+
+pub mod transaction;
+pub use transaction::{Transaction, recover, transaction};
+
+pub mod stream;
+pub use stream::{RecordIter, iter_records, stream_count_records, stream_find_by};
+
+pub mod spatial;
+pub use spatial::{
+    Space, create_spatial_index, drop_spatial_index, nearest, within_bounds, within_radius,
+};
+
+pub mod query;
+pub use query::{Cursor, Page, Query, page, query};
+
+pub mod records;
+pub use records::{Records, compare_ids};
+
 const DB_PATH: &str = "./db";
 
 // Structure for data storage *********************************************************************
@@ -32,26 +99,89 @@ const DB_PATH: &str = "./db";
 pub struct TableData<T: Serialize> {
     pub table: String,
     pub next_id: String,
-    pub records: HashMap<String, T>,
+    pub records: Records<T>,
+    /// How many times each record has been updated. Records never updated are left out.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, u64>,
+    /// When each record expires, in milliseconds since the Unix epoch. Records that never expire
+    /// are left out.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub expires: BTreeMap<String, u64>,
+    /// The version of the shape of the records, moved along by migrations.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub schema_version: u32,
+}
+
+impl<T: Serialize> TableData<T> {
+    /// The version of a record, which starts at 0 and goes up by one on every update.
+    pub fn version(&self, id: &str) -> u64 {
+        self.versions.get(id).copied().unwrap_or(0)
+    }
+
+    // Moves the versions and expiry times along with a write, so they are saved in the same file
+    // as the records
+    pub(crate) fn record_changes(&mut self, changes: &[Change]) {
+        for change in changes {
+            match change {
+                Change::Insert { id, .. } => {
+                    self.versions.remove(id);
+                }
+                Change::Update { id, .. } => {
+                    *self.versions.entry(id.clone()).or_insert(0) += 1;
+                }
+                Change::Delete { id, .. } => {
+                    self.versions.remove(id);
+                    self.expires.remove(id);
+                }
+                Change::Clear => {
+                    self.versions.clear();
+                    self.expires.clear();
+                }
+            }
+        }
+    }
+
+    // Whether the table holds a record `id` which has not expired
+    pub(crate) fn is_live(&self, id: &str) -> bool {
+        self.records.contains_key(id) && !expiry::has_expired(self.expires.get(id), expiry::now())
+    }
+
+    // Drops the records that have expired, which are kept on disk until purged
+    fn into_live_records(self) -> Records<T> {
+        let mut records = self.records;
+
+        let now = expiry::now();
+
+        for (id, expires) in &self.expires {
+            if expiry::has_expired(Some(expires), now) {
+                records.remove(id);
+            }
+        }
+
+        records
+    }
 }
 
 // Public functions *******************************************************************************
 
 pub fn update_table<T: Serialize>(table: &str, t: &T) -> Result<()> {
-    let db_table = db_table(table);
+    let _lock = lock_writes()?;
 
-    let writer = buffed_writer(db_table)?;
-
-    let data = &create_base_data(table, t);
+    // Kept as raw JSON so hooks can rewrite it without changing how it is written
+    let record = serde_json::value::to_raw_value(t)?;
 
-    serde_json::to_writer(writer, data)?;
+    let mut changes = [Change::Clear, Change::insert("0", t)?];
 
-    Ok(())
+    commit_table(table, &mut create_base_data(table, record), &mut changes)
 }
 
 pub fn create_table<T: Serialize>(table: &str, t: &T) -> Result<()> {
+    let _lock = lock_writes()?;
+
     create_db_dir()?;
 
+    namespace::check_namespace(table)?;
+
     let db_table = db_table(table);
 
     if db_table.exists() {
@@ -64,12 +194,16 @@ pub fn create_table<T: Serialize>(table: &str, t: &T) -> Result<()> {
 
     serde_json::to_writer(writer, data)?;
 
-    Ok(())
+    meta::touch(table)
 }
 
 pub fn create_empty_table<T: Serialize>(table: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
     create_db_dir()?;
 
+    namespace::check_namespace(table)?;
+
     let db_table = db_table(table);
 
     if db_table.exists() {
@@ -78,21 +212,35 @@ pub fn create_empty_table<T: Serialize>(table: &str) -> Result<()> {
 
     let file = File::create(db_table)?;
 
-    let record: HashMap<String, T> = HashMap::new();
-
-    let data = TableData {
+    let data: TableData<T> = TableData {
         table: table.to_string(),
         next_id: "0".to_string(),
-        records: record,
+        records: Records::new(),
+        versions: BTreeMap::new(),
+        expires: BTreeMap::new(),
+        schema_version: migration::latest_version(table),
     };
 
     serde_json::to_writer(file, &data)?;
 
-    Ok(())
+    meta::touch(table)
 }
 
 pub fn read_table(table: &str) -> Result<String> {
-    let db_table = Path::new(DB_PATH).join(table.to_owned());
+    let contents = read_contents(table)?;
+
+    if migration::outdated(table, &contents)? {
+        migration::migrate_on_read(table)?;
+
+        return read_contents(table);
+    }
+
+    Ok(contents)
+}
+
+// Reads a table file as it is on disk, without migrating it
+fn read_contents(table: &str) -> Result<String> {
+    let db_table = Path::new(DB_PATH).join(table);
 
     let mut file = match File::open(db_table) {
         Ok(file) => file,
@@ -109,11 +257,185 @@ pub fn read_table(table: &str) -> Result<String> {
     Ok(buffer)
 }
 
+/// Removes `table` along with its indexes, constraints, schema and metadata.
+///
+/// Fails if other tables still refer to its records. That error, like any other which is not
+/// about IO, comes back inside an `io::Error` of kind `Other`, and `?` turns it back into the
+/// original `Error`.
 pub fn drop_table(table: &str) -> io::Result<()> {
+    remove_table(table).map_err(|err| match err {
+        Error::Io(err) => err,
+        err => io::Error::other(err),
+    })
+}
+
+fn remove_table(table: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
     let table_path = Path::new(DB_PATH).join(table);
 
+    if table_path.exists() {
+        references::enforce_drop(table)?;
+    }
+
     fs::remove_file(table_path)?;
 
+    for kind in SIDECARS {
+        match fs::remove_file(sidecar(table, kind)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+
+    feed::publish_drop(table);
+
+    Ok(())
+}
+
+/// Renames `from` to `to`, along with its indexes, constraints, schema and references.
+///
+/// Fails with `Error::TableExists` if `to` exists. Subscribers of `from` see it dropped. Hooks
+/// and migrations are registered by name, so they stay with `from`. A rename cut short by a crash
+/// is finished by `recover`, like a transaction.
+pub fn rename_table(from: &str, to: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    if !table_exists(from) {
+        return Err(Error::NoSuchTable(from.to_string()));
+    }
+
+    if table_exists(to) {
+        return Err(Error::TableExists(to.to_string()));
+    }
+
+    namespace::check_namespace(to)?;
+
+    transaction::rename_table(from, to)?;
+
+    feed::publish_drop(from);
+
+    Ok(())
+}
+
+// Moves a table and everything kept alongside it to its new name. Each step can be taken again,
+// so a rename cut short anywhere can be finished by running this once more.
+fn finish_rename(from: &str, to: &str) -> Result<()> {
+    // The new file is complete before the old one goes, so the table is never missing
+    if db_table(from).exists() {
+        let mut data = get_table::<Box<RawValue>>(from)?;
+
+        data.table = to.to_string();
+
+        upgrade_table(to, &data)?;
+    }
+
+    for kind in SIDECARS {
+        match move_file(&sidecar(from, kind), &sidecar(to, kind)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+
+    match fs::remove_file(db_table(from)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+
+    references::enforce_rename(from, to)
+}
+
+/// Copies `from` to a new table `to`, along with its indexes, unique constraints and schema.
+///
+/// Fails with `Error::TableExists` if `to` exists.
+pub fn copy_table(from: &str, to: &str) -> Result<()> {
+    copy_table_where::<IgnoredAny, _>(from, to, |_| true)
+}
+
+/// Like `copy_table`, but only copies the records matching `predicate`. Ids stay the same.
+pub fn copy_table_where<T, F>(from: &str, to: &str, predicate: F) -> Result<()>
+where
+    T: for<'a> Deserialize<'a>,
+    F: Fn(&T) -> bool,
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<Box<RawValue>>(from)?;
+
+    if table_exists(to) {
+        return Err(Error::TableExists(to.to_string()));
+    }
+
+    namespace::check_namespace(to)?;
+
+    let mut records = Records::new();
+
+    let mut changes = vec![Change::Clear];
+
+    for (id, record) in data.records {
+        if predicate(&serde_json::from_str(record.get())?) {
+            changes.push(Change::Insert {
+                id: id.clone(),
+                new: serde_json::from_str(record.get())?,
+            });
+
+            records.insert(id, record);
+        }
+    }
+
+    data.table = to.to_string();
+    data.versions.retain(|id, _| records.contains_key(id));
+    data.expires.retain(|id, _| records.contains_key(id));
+    data.records = records;
+
+    // Whatever is derived from the records is rebuilt from the copies, below
+    for kind in SIDECARS.iter().filter(|&&kind| kind != meta::SIDECAR) {
+        match fs::copy(sidecar(from, kind), sidecar(to, kind)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result.map(|_| ())?,
+        }
+    }
+
+    upgrade_table(to, &data)?;
+
+    mirror(to, &changes)
+}
+
+/// Moves `table` out of this database into the database kept in the directory `dest`, along
+/// with its indexes, unique constraints and schema.
+///
+/// Fails with `Error::TableExists` if `dest` already holds such a table. To the tables left
+/// behind, moving a table away is the same as dropping it, so references to it are enforced as
+/// by `drop_table`.
+pub fn move_table<P: AsRef<Path>>(table: &str, dest: P) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let dest_table = dest.as_ref().join(table);
+
+    if !table_exists(table) {
+        return Err(Error::NoSuchTable(table.to_string()));
+    }
+
+    if dest_table.exists() {
+        return Err(Error::TableExists(table.to_string()));
+    }
+
+    references::enforce_drop(table)?;
+
+    if let Some(dir) = dest_table.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    move_file(&db_table(table), &dest_table)?;
+
+    for kind in SIDECARS {
+        match move_file(&sidecar(table, kind), &sidecar_of(&dest_table, kind)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+
+    feed::publish_drop(table);
+
     Ok(())
 }
 
@@ -121,17 +443,23 @@ pub fn append_records<T>(table: &str, t: T) -> Result<()>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
+    let _lock = lock_writes()?;
+
     let mut data = get_table(table)?;
 
     let increased_next_id = data.next_id.parse::<i32>()?;
 
     let new_id = increased_next_id + 1;
 
-    data.records.insert(increased_next_id.to_string(), t);
+    let id = increased_next_id.to_string();
+
+    let mut changes = [Change::insert(&id, &t)?];
+
+    data.records.insert(id, t);
 
     data.next_id = new_id.to_string();
 
-    upgrade_table(table, &data)
+    commit_table(table, &mut data, &mut changes)
 }
 
 pub fn get_table<T>(table: &str) -> Result<TableData<T>>
@@ -143,122 +471,203 @@ where
     serde_json::from_str(&result).map_err(Error::from)
 }
 
-pub fn get_table_records<T>(table: &str) -> Result<HashMap<String, T>>
+pub fn get_table_records<T>(table: &str) -> Result<Records<T>>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    Ok(get_table(table)?.records)
+    Ok(get_table(table)?.into_live_records())
 }
 
 pub fn find<T>(table: &str, id: &str) -> Result<T>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    get_table_records(table)?.remove(id).ok_or(Error::NoSuchKey)
+    let contents = read_table(table)?;
+
+    let raw = find_live(&contents, id)?;
+
+    serde_json::from_str(raw).map_err(Error::from)
 }
 
-pub fn delete<T>(table: &str, id: &str) -> Result<()>
+pub fn find_versioned<T>(table: &str, id: &str) -> Result<(T, u64)>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    let mut current_table: HashMap<String, T> = get_table_records(table)?;
+    let contents = read_table(table)?;
+
+    let raw = find_live(&contents, id)?;
+
+    let version = match stream::find_raw_in(&contents, "versions", id)? {
+        Some(version) => serde_json::from_str(version)?,
+        None => 0,
+    };
 
-    current_table.remove(id);
+    Ok((serde_json::from_str(raw)?, version))
+}
 
-    update_table(table, &current_table)
+pub fn delete<T>(table: &str, id: &str) -> Result<T>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    delete_if_exists(table, id)?.ok_or(Error::NoSuchKey)
+}
+
+pub fn delete_if_exists<T>(table: &str, id: &str) -> Result<Option<T>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<T>(table)?;
+
+    if !data.is_live(id) {
+        return Ok(None);
+    }
+
+    let removed = match data.records.remove(id) {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    commit_table(table, &mut data, &mut [Change::delete(id, &removed)?])?;
+
+    Ok(Some(removed))
 }
 
 pub fn json_find<T>(table: &str, id: &str) -> Result<String>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    let incoming_record: T = find(table, id)?;
+    let contents = read_table(table)?;
 
-    serde_json::to_string(&incoming_record).map_err(Error::from)
+    let raw = find_live(&contents, id)?;
+
+    Ok(raw.to_string())
 }
 
 pub fn json_table_records<T>(table: &str) -> Result<String>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    let records: HashMap<String, T> = get_table_records(table)?;
+    let records: Records<T> = get_table_records(table)?;
 
     serde_json::to_string(&records).map_err(Error::from)
 }
 
 pub fn store_json(table: &str, json: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
     create_db_dir()?;
 
+    namespace::check_namespace(table)?;
+
     let db_table = db_table(table);
 
     if db_table.exists() {
         return Ok(());
     }
 
-    let writer = buffed_writer(db_table)?;
+    schema::check_json(table, json)?;
 
-    serde_json::to_writer(writer, json)?;
+    let mut writer = buffed_writer(db_table)?;
 
-    Ok(())
+    writer.write_all(json.as_bytes())?;
+    writer.flush()?;
+
+    meta::touch(table)?;
+
+    mirror(table, &[Change::Clear])
 }
 
 pub fn update_json(table: &str, json: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
     create_db_dir()?;
 
+    namespace::check_namespace(table)?;
+
     let db_table = db_table(table);
 
-    let writer = buffed_writer(db_table)?;
+    schema::check_json(table, json)?;
 
-    serde_json::to_writer(writer, json)?;
+    let mut writer = buffed_writer(db_table)?;
 
-    Ok(())
+    writer.write_all(json.as_bytes())?;
+    writer.flush()?;
+
+    meta::touch(table)?;
+
+    mirror(table, &[Change::Clear])
 }
 
 pub fn count_records<T>(table: &str) -> Result<usize>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    let records = get_table_records::<T>(table)?;
+    #[derive(Deserialize)]
+    struct Ids {
+        records: Records<IgnoredAny>,
+        #[serde(default)]
+        expires: BTreeMap<String, u64>,
+    }
+
+    let ids: Ids = serde_json::from_str(&read_table(table)?)?;
 
-    Ok(records.len())
+    let now = expiry::now();
+
+    Ok(ids
+        .records
+        .keys()
+        .filter(|id| !expiry::has_expired(ids.expires.get(*id), now))
+        .count())
 }
 
 pub fn batch_insert<T>(table: &str, records: Vec<T>) -> Result<()>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
+    let _lock = lock_writes()?;
+
     let mut data = get_table(table)?;
 
     let mut next_id = data.next_id.parse::<i32>()?;
 
+    let mut changes = Vec::with_capacity(records.len());
+
     for record in records {
-        data.records.insert(next_id.to_string(), record);
+        let id = next_id.to_string();
+
+        changes.push(Change::insert(&id, &record)?);
+
+        data.records.insert(id, record);
 
         next_id += 1;
     }
 
     data.next_id = next_id.to_string();
 
-    upgrade_table(table, &data)
+    commit_table(table, &mut data, &mut changes)
 }
 
 pub fn update_record<T>(table: &str, id: &str, record: T) -> Result<()>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
-    let mut data = get_table(table)?;
-
-    if !data.records.contains_key(id) {
-        return Err(Error::NoSuchKey);
-    }
-
-    data.records.insert(id.to_string(), record);
+    replace_record(table, id, None, record).map(|_| ())
+}
 
-    upgrade_table(table, &data)
+/// Updates a record only if it is still at `expected_version`, returning its new version.
+///
+/// Fails with `Error::Conflict` if the record was updated since that version was read.
+pub fn update_if_version<T>(table: &str, id: &str, expected_version: u64, record: T) -> Result<u64>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    replace_record(table, id, Some(expected_version), record)
 }
 
 pub fn table_exists(table: &str) -> bool {
-    db_table(table).exists()
+    db_table(table).is_file()
 }
 
 pub fn list_tables() -> io::Result<Vec<String>> {
@@ -271,7 +680,11 @@ pub fn list_tables() -> io::Result<Vec<String>> {
     for entry in fs::read_dir(DB_PATH)? {
         let entry = entry?;
 
-        if let Some(name) = entry.file_name().to_str() {
+        // Dotfiles are our own scratch files and directories are namespaces, neither are tables
+        if let Some(name) = entry.file_name().to_str()
+            && !name.starts_with('.')
+            && entry.file_type()?.is_file()
+        {
             tables.push(name.to_string());
         }
     }
@@ -279,14 +692,14 @@ pub fn list_tables() -> io::Result<Vec<String>> {
     Ok(tables)
 }
 
-pub fn find_by<T, F>(table: &str, predicate: F) -> Result<HashMap<String, T>>
+pub fn find_by<T, F>(table: &str, predicate: F) -> Result<Records<T>>
 where
     T: for<'a> Deserialize<'a> + Serialize,
     F: Fn(&T) -> bool,
 {
     let all_records = get_table_records::<T>(table)?;
 
-    let mut matching_records = HashMap::new();
+    let mut matching_records = Records::new();
 
     for (id, record) in all_records {
         if predicate(&record) {
@@ -297,22 +710,425 @@ where
     Ok(matching_records)
 }
 
+pub fn range<'r, T, R>(table: &str, ids: R) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    R: RangeBounds<&'r str>,
+{
+    let contents = read_table(table)?;
+
+    raw_records(&contents)?
+        .range(ids)
+        .map(|(id, record)| Ok((id.clone(), serde_json::from_str(record.get())?)))
+        .collect()
+}
+
+pub fn since<T>(table: &str, id: &str) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    range(table, (Bound::Excluded(id), Bound::Unbounded))
+}
+
 pub fn clear_table<T>(table: &str) -> Result<()>
 where
     T: for<'a> Deserialize<'a> + Serialize,
 {
+    let _lock = lock_writes()?;
+
     let mut data = get_table::<T>(table)?;
 
     data.records.clear();
 
     data.next_id = "0".to_string();
 
-    upgrade_table(table, &data)
+    commit_table(table, &mut data, &mut [Change::Clear])
+}
+
+pub fn delete_where<T, F>(table: &str, predicate: F) -> Result<Vec<String>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    F: Fn(&T) -> bool,
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<T>(table)?;
+
+    let ids: Vec<String> = data
+        .records
+        .iter()
+        .filter(|&(id, record)| data.is_live(id) && predicate(record))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    if ids.is_empty() {
+        return Ok(ids);
+    }
+
+    let mut changes = Vec::with_capacity(ids.len());
+
+    for id in &ids {
+        if let Some(old) = data.records.remove(id) {
+            changes.push(Change::delete(id, &old)?);
+        }
+    }
+
+    commit_table(table, &mut data, &mut changes)?;
+
+    Ok(ids)
+}
+
+pub fn update_where<T, F, U>(table: &str, predicate: F, mut update: U) -> Result<Vec<String>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    F: Fn(&T) -> bool,
+    U: FnMut(&mut T),
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table::<T>(table)?;
+
+    let mut ids = Vec::new();
+
+    let mut changes = Vec::new();
+
+    let now = expiry::now();
+
+    for (id, record) in data.records.iter_mut() {
+        if !expiry::has_expired(data.expires.get(id), now) && predicate(record) {
+            let old = serde_json::to_value(&*record)?;
+
+            update(record);
+
+            changes.push(Change::Update {
+                id: id.clone(),
+                old,
+                new: serde_json::to_value(&*record)?,
+            });
+
+            ids.push(id.clone());
+        }
+    }
+
+    if ids.is_empty() {
+        return Ok(ids);
+    }
+
+    commit_table(table, &mut data, &mut changes)?;
+
+    Ok(ids)
 }
 
 // Private functions ******************************************************************************
 
-fn db_table(table: &str) -> std::path::PathBuf {
+// Every kind of file kept next to a table, removed along with it
+const SIDECARS: &[&str] = &[
+    constraints::SIDECAR,
+    index::SIDECAR,
+    meta::SIDECAR,
+    schema::SIDECAR,
+    search::SIDECAR,
+    spatial::SIDECAR,
+];
+
+// A record level change made by a write, handed to everything that mirrors a table's contents
+#[derive(Clone)]
+pub(crate) enum Change {
+    Insert { id: String, new: Value },
+    Update { id: String, old: Value, new: Value },
+    Delete { id: String, old: Value },
+    Clear,
+}
+
+impl Change {
+    fn insert<T: Serialize>(id: &str, new: &T) -> Result<Change> {
+        Ok(Change::Insert {
+            id: id.to_string(),
+            new: serde_json::to_value(new)?,
+        })
+    }
+
+    fn delete<T: Serialize>(id: &str, old: &T) -> Result<Change> {
+        Ok(Change::Delete {
+            id: id.to_string(),
+            old: serde_json::to_value(old)?,
+        })
+    }
+}
+
+// Serializes every write in the process. The lock is reentrant, so a thread already holding it,
+// such as one dropping a table others refer to, can still call the other write functions.
+static WRITER: Mutex<(Option<ThreadId>, usize)> = Mutex::new((None, 0));
+static WRITER_RELEASED: Condvar = Condvar::new();
+
+pub(crate) struct WriteLock;
+
+// Takes the lock for a write. A transaction left half committed by a crash is finished first, so
+// its journal can never be replayed over anything written after it.
+pub(crate) fn lock_writes() -> Result<WriteLock> {
+    transaction::refuse_if_open()?;
+
+    lock_for_upkeep()
+}
+
+// Takes the lock even inside a transaction, for writes which only bring a table up to date
+// without changing what it holds
+pub(crate) fn lock_for_upkeep() -> Result<WriteLock> {
+    let (lock, first) = take_writer();
+
+    if first {
+        transaction::recover()?;
+    }
+
+    Ok(lock)
+}
+
+// Takes the lock without looking for an unfinished transaction, for `recover` itself
+pub(crate) fn lock_for_recovery() -> WriteLock {
+    take_writer().0
+}
+
+// Returns the lock along with whether this thread did not hold it already
+fn take_writer() -> (WriteLock, bool) {
+    let me = thread::current().id();
+
+    let mut writer = WRITER.lock().unwrap_or_else(|err| err.into_inner());
+
+    loop {
+        match writer.0 {
+            None => {
+                *writer = (Some(me), 1);
+
+                return (WriteLock, true);
+            }
+            Some(owner) if owner == me => {
+                writer.1 += 1;
+
+                return (WriteLock, false);
+            }
+            Some(_) => {
+                writer = WRITER_RELEASED
+                    .wait(writer)
+                    .unwrap_or_else(|err| err.into_inner());
+            }
+        }
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        let mut writer = WRITER.lock().unwrap_or_else(|err| err.into_inner());
+
+        writer.1 -= 1;
+
+        if writer.1 == 0 {
+            writer.0 = None;
+
+            WRITER_RELEASED.notify_all();
+        }
+    }
+}
+
+fn replace_record<T>(table: &str, id: &str, expected_version: Option<u64>, record: T) -> Result<u64>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let _lock = lock_writes()?;
+
+    let mut data = get_table(table)?;
+
+    if !data.is_live(id) {
+        return Err(Error::NoSuchKey);
+    }
+
+    let current_version = data.version(id);
+
+    if expected_version.is_some_and(|expected| expected != current_version) {
+        return Err(Error::Conflict { current_version });
+    }
+
+    let new = serde_json::to_value(&record)?;
+
+    let old = match data.records.insert(id.to_string(), record) {
+        Some(old) => serde_json::to_value(old)?,
+        None => return Err(Error::NoSuchKey),
+    };
+
+    let mut changes = [Change::Update {
+        id: id.to_string(),
+        old,
+        new,
+    }];
+
+    commit_table(table, &mut data, &mut changes)?;
+
+    Ok(data.version(id))
+}
+
+// Writes a modified table back and brings everything derived from it up to date
+fn commit_table<T>(table: &str, data: &mut TableData<T>, changes: &mut [Change]) -> Result<()>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    // References may cascade to other tables, which have to be committed together with this one
+    if references::involved(table)? {
+        return transaction::commit_table(table, data, changes);
+    }
+
+    hooks::before(table, data, changes)?;
+
+    schema::check(table, changes)?;
+
+    constraints::check(table, data, changes)?;
+
+    data.record_changes(changes);
+
+    upgrade_table(table, data)?;
+
+    mirror(table, changes)?;
+
+    hooks::after(table, changes);
+
+    Ok(())
+}
+
+// Replays a committed write onto everything kept alongside the table, then tells subscribers
+fn mirror(table: &str, changes: &[Change]) -> Result<()> {
+    reindex(table, changes)?;
+
+    feed::publish(table, changes);
+
+    Ok(())
+}
+
+// Replays a committed write onto the indexes of a table, without telling anyone
+fn reindex(table: &str, changes: &[Change]) -> Result<()> {
+    index::apply(table, changes)?;
+
+    search::apply(table, changes)?;
+
+    spatial::apply(table, changes)
+}
+
+// Reads a table while skipping over its records, for when only the ids of the live ones matter
+pub(crate) fn get_table_ids(table: &str) -> Result<Records<IgnoredAny>> {
+    #[derive(Deserialize)]
+    struct Ids {
+        records: Records<IgnoredAny>,
+        #[serde(default)]
+        expires: BTreeMap<String, u64>,
+    }
+
+    let mut ids: Ids = serde_json::from_str(&read_table(table)?)?;
+
+    let now = expiry::now();
+
+    for (id, expires) in &ids.expires {
+        if expiry::has_expired(Some(expires), now) {
+            ids.records.remove(id);
+        }
+    }
+
+    Ok(ids.records)
+}
+
+// Finds the unparsed JSON of one record, as long as it has not expired
+fn find_live<'a>(contents: &'a str, id: &str) -> Result<&'a str> {
+    let raw = stream::find_raw(contents, id)?.ok_or(Error::NoSuchKey)?;
+
+    if let Some(expires) = stream::find_raw_in(contents, "expires", id)?
+        && expiry::has_expired(Some(&serde_json::from_str(expires)?), expiry::now())
+    {
+        return Err(Error::NoSuchKey);
+    }
+
+    Ok(raw)
+}
+
+// Looks up several records at once, in the order their ids are given. Missing and expired ids
+// are skipped. Only the records asked for are parsed, every other is stepped over.
+pub(crate) fn find_many<T>(table: &str, ids: &[String]) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let contents = read_table(table)?;
+
+    let mut records = stream::find_raw_many(&contents, Some("records"), ids)?;
+
+    let expires = stream::find_raw_many(&contents, Some("expires"), ids)?;
+
+    let now = expiry::now();
+
+    let mut found = Vec::with_capacity(ids.len());
+
+    for id in ids {
+        if let Some(expires) = expires.get(id)
+            && expiry::has_expired(Some(&serde_json::from_str(expires)?), now)
+        {
+            continue;
+        }
+
+        if let Some(raw) = records.remove(id) {
+            found.push((id.clone(), serde_json::from_str(raw)?));
+        }
+    }
+
+    Ok(found)
+}
+
+// Splits a table's contents into records left as unparsed slices of JSON, so only the records
+// actually wanted pay for deserializing
+fn raw_records(contents: &str) -> Result<Records<&RawValue>> {
+    #[derive(Deserialize)]
+    struct RawTable<'a> {
+        #[serde(borrow)]
+        records: Records<&'a RawValue>,
+        #[serde(default)]
+        expires: BTreeMap<String, u64>,
+    }
+
+    let mut data: RawTable = serde_json::from_str(contents)?;
+
+    let now = expiry::now();
+
+    for (id, expires) in &data.expires {
+        if expiry::has_expired(Some(expires), now) {
+            data.records.remove(id);
+        }
+    }
+
+    Ok(data.records)
+}
+
+// The path of a file of the given kind that belongs to a table, hidden from `list_tables`
+pub(crate) fn sidecar(table: &str, kind: &str) -> PathBuf {
+    sidecar_of(&db_table(table), kind)
+}
+
+fn sidecar_of(db_table: &Path, kind: &str) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+
+    name.push(db_table.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(kind);
+
+    db_table.with_file_name(name)
+}
+
+// Resolves a top-level field name or a JSON pointer such as "/address/city" to a JSON pointer
+pub(crate) fn json_pointer(path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') {
+        return path.to_string();
+    }
+
+    format!("/{}", path.replace('~', "~0").replace('/', "~1"))
+}
+
+pub(crate) fn db_table(table: &str) -> std::path::PathBuf {
     Path::new(DB_PATH).join(table)
 }
 
@@ -325,19 +1141,58 @@ fn buffed_writer(db_table: PathBuf) -> Result<BufWriter<File>> {
 }
 
 fn upgrade_table<T: Serialize>(table: &str, t: &T) -> Result<()> {
-    let db_table = db_table(table);
+    write_json(&db_table(table), t)?;
 
-    let file = File::create(db_table)?;
+    meta::touch(table)
+}
 
-    let writer = BufWriter::new(file);
+// Writes to a temporary file first and renames it into place, so a failure part way through
+// never leaves a half-written file behind.
+pub(crate) fn write_json<T: Serialize>(path: &Path, t: &T) -> Result<()> {
+    let tmp_table = tmp_table(path);
+
+    let mut writer = buffed_writer(tmp_table.clone())?;
+
+    let written = serde_json::to_writer(&mut writer, t)
+        .map_err(Error::from)
+        .and_then(|_| writer.flush().map_err(Error::from));
+
+    drop(writer);
+
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_table);
+
+        return Err(err);
+    }
 
-    serde_json::to_writer(writer, t)?;
+    fs::rename(tmp_table, path)?;
 
     Ok(())
 }
 
+// Moves a file, copying it instead when it has to cross to another file system
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+fn tmp_table(db_table: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+
+    name.push(db_table.file_name().unwrap_or_default());
+    name.push(".tmp");
+
+    db_table.with_file_name(name)
+}
+
 fn create_base_data<T: Serialize>(table: &str, t: T) -> TableData<T> {
-    let mut record = HashMap::new();
+    let mut record = Records::new();
 
     record.insert("0".to_string(), t);
 
@@ -345,9 +1200,16 @@ fn create_base_data<T: Serialize>(table: &str, t: T) -> TableData<T> {
         table: table.to_string(),
         next_id: "1".to_string(),
         records: record,
+        versions: BTreeMap::new(),
+        expires: BTreeMap::new(),
+        schema_version: migration::latest_version(table),
     }
 }
 
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
 fn create_db_dir() -> Result<()> {
     if Path::new(DB_PATH).exists() {
         return Ok(());
@@ -361,8 +1223,11 @@ fn create_db_dir() -> Result<()> {
 // Tests ******************************************************************************************
 
 #[cfg(test)]
+// The oldest tests predate these lints and are kept as they were written
+#[allow(clippy::explicit_auto_deref, clippy::needless_borrow)]
 mod the_db {
     use super::*;
+    use serde_json::json;
 
     const TEST: &str = "test";
     const COORDS: Coordinates = Coordinates { x: 42, y: 9000 };
@@ -475,22 +1340,52 @@ mod the_db {
         Ok(())
     }
 
+    #[test]
+    fn can_store_and_update_json() -> Result<()> {
+        let table = "test_json";
+
+        let j =
+            "{\"table\":\"test_json\",\"next_id\":\"1\",\"records\":{\"0\":{\"x\":42,\"y\":9000}}}";
+
+        store_json(table, j)?;
+
+        assert_eq!(j, read_table(table)?);
+        assert_eq!(COORDS, find(table, "0")?);
+
+        let k = "{\"table\":\"test_json\",\"next_id\":\"1\",\"records\":{\"0\":{\"x\":1,\"y\":2}}}";
+
+        update_json(table, k)?;
+
+        assert_eq!(get_table::<Coordinates>(table)?.records.len(), 1);
+        assert_eq!(Coordinates { x: 1, y: 2 }, find(table, "0")?);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
     #[test]
     fn can_delete_table_data_by_id() -> Result<()> {
         create_table("test_6", &COORDS)?;
 
         assert_eq!(COORDS, find("test_6", "0")?);
 
+        append_records("test_6", Coordinates { x: 1, y: 2 })?;
+
         let del = delete::<Coordinates>;
 
-        del("test_6", "0")?;
+        assert_eq!(COORDS, del("test_6", "0")?);
 
         let table = read_table("test_6")?;
         assert_eq!(
             table,
-            "{\"table\":\"test_6\",\"next_id\":\"1\",\"records\":{\"0\":{}}}"
+            "{\"table\":\"test_6\",\"next_id\":\"2\",\"records\":{\"1\":{\"x\":1,\"y\":2}}}"
         );
 
+        assert!(matches!(del("test_6", "0"), Err(Error::NoSuchKey)));
+
+        assert_eq!(None, delete_if_exists::<Coordinates>("test_6", "0")?);
+
         drop_table("test_6")?;
 
         Ok(())
@@ -678,6 +1573,111 @@ mod the_db {
         Ok(())
     }
 
+    #[test]
+    fn can_test_update_if_version() -> Result<()> {
+        let table_name = "version_test";
+
+        create_table(table_name, &COORDS)?;
+
+        append_records(table_name, COORDS)?;
+
+        assert_eq!(find_versioned::<Coordinates>(table_name, "0")?, (COORDS, 0));
+
+        let moved = Coordinates { x: 1, y: 2 };
+
+        assert_eq!(update_if_version(table_name, "0", 0, moved)?, 1);
+
+        update_record(table_name, "0", COORDS)?;
+
+        let result = update_if_version(table_name, "0", 1, Coordinates { x: 3, y: 4 });
+
+        assert!(matches!(
+            result,
+            Err(Error::Conflict { current_version: 2 })
+        ));
+
+        assert_eq!(find_versioned::<Coordinates>(table_name, "0")?, (COORDS, 2));
+        assert_eq!(find_versioned::<Coordinates>(table_name, "1")?.1, 0);
+
+        let result = update_if_version(table_name, "7", 0, COORDS);
+
+        assert!(matches!(result, Err(Error::NoSuchKey)));
+
+        delete::<Coordinates>(table_name, "0")?;
+
+        assert!(get_table::<Coordinates>(table_name)?.versions.is_empty());
+
+        drop_table(table_name)?;
+        Ok(())
+    }
+
+    #[test]
+    fn can_rename_copy_and_move_tables() -> Result<()> {
+        let (table, renamed, copied) = ("rename_test", "rename_test_renamed", "rename_test_copy");
+        let owners = "rename_test_owners";
+
+        create_table(table, &COORDS)?;
+        append_records(table, Coordinates { x: 1, y: 2 })?;
+        create_index(table, "x", "x")?;
+
+        create_table(owners, &json!({"coordinates": "1"}))?;
+        add_reference(owners, "coordinates", table, OnDelete::Restrict)?;
+
+        create_empty_table::<Coordinates>(copied)?;
+
+        assert!(matches!(
+            rename_table(table, copied),
+            Err(Error::TableExists(_))
+        ));
+
+        drop_table(copied)?;
+
+        rename_table(table, renamed)?;
+
+        assert!(!table_exists(table));
+        assert_eq!(get_table::<Coordinates>(renamed)?.table, renamed);
+        assert_eq!(find_by_index::<Coordinates, _>(renamed, "x", &1)?.len(), 1);
+
+        assert!(matches!(
+            delete::<Coordinates>(renamed, "1"),
+            Err(Error::ReferenceViolation { .. })
+        ));
+
+        copy_table_where::<Coordinates, _>(renamed, copied, |c| c.x < 10)?;
+
+        let copy = get_table::<Coordinates>(copied)?;
+
+        assert_eq!(copy.table, copied);
+        assert_eq!(copy.next_id, "2");
+        assert_eq!(copy.records.keys().collect::<Vec<_>>(), vec!["1"]);
+        assert_eq!(find_by_index::<Coordinates, _>(copied, "x", &42)?.len(), 0);
+
+        let elsewhere = std::env::temp_dir().join("rust_bucket_move_test");
+
+        let _ = fs::remove_dir_all(&elsewhere);
+
+        move_table(copied, &elsewhere)?;
+
+        assert!(!table_exists(copied));
+        assert!(elsewhere.join(copied).exists());
+        assert!(sidecar_of(&elsewhere.join(copied), index::SIDECAR).exists());
+
+        copy_table(renamed, copied)?;
+
+        assert!(matches!(
+            move_table(copied, &elsewhere),
+            Err(Error::TableExists(_))
+        ));
+
+        fs::remove_dir_all(&elsewhere)?;
+
+        drop_table(owners)?;
+        drop_table(renamed)?;
+        drop_table(copied)?;
+
+        Ok(())
+    }
+
     #[test]
     fn can_test_table_exists() -> Result<()> {
         let table_name = "exists_test";
@@ -750,6 +1750,111 @@ mod the_db {
         Ok(())
     }
 
+    #[test]
+    fn can_test_delete_where() -> Result<()> {
+        let table_name = "delete_where_test";
+
+        create_empty_table::<Coordinates>(table_name)?;
+
+        batch_insert(
+            table_name,
+            vec![
+                Coordinates { x: 1, y: 10 },
+                Coordinates { x: 2, y: 20 },
+                Coordinates { x: 3, y: 10 },
+            ],
+        )?;
+
+        let mut deleted = delete_where::<Coordinates, _>(table_name, |coord| coord.y == 10)?;
+
+        deleted.sort();
+
+        assert_eq!(deleted, vec!["0", "2"]);
+
+        let data = get_table::<Coordinates>(table_name)?;
+
+        assert_eq!(data.records.len(), 1);
+
+        assert_eq!(data.next_id, "3");
+
+        assert!(delete_where::<Coordinates, _>(table_name, |coord| coord.y == 10)?.is_empty());
+
+        drop_table(table_name)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_test_update_where() -> Result<()> {
+        let table_name = "update_where_test";
+
+        create_empty_table::<Coordinates>(table_name)?;
+
+        batch_insert(
+            table_name,
+            vec![
+                Coordinates { x: 1, y: 10 },
+                Coordinates { x: 2, y: 20 },
+                Coordinates { x: 3, y: 10 },
+            ],
+        )?;
+
+        let mut updated = update_where::<Coordinates, _, _>(
+            table_name,
+            |coord| coord.y == 10,
+            |coord| coord.y = 99,
+        )?;
+
+        updated.sort();
+
+        assert_eq!(updated, vec!["0", "2"]);
+
+        assert_eq!(Coordinates { x: 1, y: 99 }, find(table_name, "0")?);
+
+        assert_eq!(Coordinates { x: 2, y: 20 }, find(table_name, "1")?);
+
+        assert_eq!(Coordinates { x: 3, y: 99 }, find(table_name, "2")?);
+
+        drop_table(table_name)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_test_range_and_since() -> Result<()> {
+        let table_name = "range_test";
+
+        create_empty_table::<Coordinates>(table_name)?;
+
+        batch_insert(
+            table_name,
+            (0..20).map(|n| Coordinates { x: n, y: n }).collect(),
+        )?;
+
+        let ids: Vec<String> = range::<Coordinates, _>(table_name, "8".."11")?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+
+        assert_eq!(ids, vec!["8", "9", "10"]);
+
+        let records = since::<Coordinates>(table_name, "17")?;
+
+        assert_eq!(
+            records,
+            vec![
+                ("18".to_string(), Coordinates { x: 18, y: 18 }),
+                ("19".to_string(), Coordinates { x: 19, y: 19 }),
+            ]
+        );
+
+        assert!(range::<Coordinates, _>(table_name, "15"..="5")?.is_empty());
+
+        drop_table(table_name)?;
+
+        Ok(())
+    }
+
     #[test]
     fn can_test_clear_table() -> Result<()> {
         let table_name = "clear_test";
@@ -0,0 +1,255 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Metadata module.
+//!
+//! Each table keeps a little metadata in a hidden file next to the table file: when it was
+//! created and last written, the format it is stored in, and tags of your own. `table_info`
+//! combines it with what the table file itself says.
+//!
+//! The metadata is kept apart from the records because several writes replace the table file
+//! whole, `update_table`, `store_json` and `update_json` among them, and the creation time and
+//! tags have to outlive those. It costs every write a read and write of this small file.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::IgnoredAny;
+
+use super::errors::{Error, Result};
+use super::{
+    Records, SIDECARS, db_table, expiry, list_tables, lock_writes, read_table, sidecar,
+    table_exists,
+};
+
+pub(crate) const SIDECAR: &str = "meta";
+
+// The layout of table files, to be bumped whenever it changes in a way older code cannot read
+const FORMAT_VERSION: u32 = 1;
+
+const CODEC: &str = "json";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Meta {
+    #[serde(default)]
+    created: Option<u64>,
+    #[serde(default)]
+    modified: Option<u64>,
+    #[serde(default)]
+    format_version: Option<u32>,
+    #[serde(default)]
+    codec: Option<String>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+/// What is known about a table.
+///
+/// Times are in milliseconds since the Unix epoch. Tables last written before metadata was kept
+/// have no creation or modification time until their next write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
+    pub table: String,
+    pub created: Option<u64>,
+    pub modified: Option<u64>,
+    /// The layout of the table file.
+    pub format_version: u32,
+    /// The version of the shape of the records, see `migrate_table`.
+    pub schema_version: u32,
+    /// How many records the table holds, leaving out expired ones.
+    pub records: usize,
+    pub codec: String,
+    pub tags: BTreeMap<String, String>,
+    /// The size in bytes of the table file and every hidden file kept alongside it.
+    pub size: u64,
+}
+
+/// Returns the metadata of `table`.
+pub fn table_info(table: &str) -> Result<TableInfo> {
+    #[derive(Deserialize)]
+    struct Summary {
+        records: Records<IgnoredAny>,
+        #[serde(default)]
+        expires: BTreeMap<String, u64>,
+        #[serde(default)]
+        schema_version: u32,
+    }
+
+    let summary: Summary = serde_json::from_str(&read_table(table)?)?;
+
+    let meta = read_meta(table)?;
+
+    let now = expiry::now();
+
+    let records = summary
+        .records
+        .keys()
+        .filter(|id| !expiry::has_expired(summary.expires.get(*id), now))
+        .count();
+
+    let mut size = fs::metadata(db_table(table))?.len();
+
+    for kind in SIDECARS {
+        match fs::metadata(sidecar(table, kind)) {
+            Ok(metadata) => size += metadata.len(),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::Io(err)),
+        }
+    }
+
+    Ok(TableInfo {
+        table: table.to_string(),
+        created: meta.created,
+        modified: meta.modified,
+        format_version: meta.format_version.unwrap_or(FORMAT_VERSION),
+        schema_version: summary.schema_version,
+        records,
+        codec: meta.codec.unwrap_or_else(|| CODEC.to_string()),
+        tags: meta.tags,
+        size,
+    })
+}
+
+/// Like `list_tables`, but returns the metadata of every table.
+///
+/// Tables dropped while being listed are left out.
+pub fn list_tables_detailed() -> Result<Vec<TableInfo>> {
+    let mut tables = Vec::new();
+
+    for table in list_tables()? {
+        match table_info(&table) {
+            Ok(info) => tables.push(info),
+            Err(Error::NoSuchTable(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Tags `table` with `value` under `key`, replacing any value it had.
+pub fn set_tag(table: &str, key: &str, value: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut meta = read_meta(table)?;
+
+    meta.tags.insert(key.to_string(), value.to_string());
+
+    write_meta(table, &meta)
+}
+
+/// Removes the tag `key` from `table`, returning whether it had one.
+pub fn remove_tag(table: &str, key: &str) -> Result<bool> {
+    let _lock = lock_writes()?;
+
+    let mut meta = read_meta(table)?;
+
+    if meta.tags.remove(key).is_none() {
+        return Ok(false);
+    }
+
+    write_meta(table, &meta)?;
+
+    Ok(true)
+}
+
+// Notes that a table file has just been written, and created if this is the first time
+pub(crate) fn touch(table: &str) -> Result<()> {
+    let mut meta = read_meta(table)?;
+
+    let now = expiry::now();
+
+    meta.created.get_or_insert(now);
+    meta.modified = Some(now);
+    meta.format_version = Some(FORMAT_VERSION);
+    meta.codec = Some(CODEC.to_string());
+
+    write_meta(table, &meta)
+}
+
+fn read_meta(table: &str) -> Result<Meta> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Ok(Meta::default());
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_meta(table: &str, meta: &Meta) -> Result<()> {
+    super::write_json(&sidecar(table, SIDECAR), meta)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_meta {
+    use super::*;
+    use crate::{append_records, create_empty_table, create_index, drop_table};
+    use serde_json::{Value, json};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn can_describe_a_table() -> Result<()> {
+        let table = "meta_info_test";
+
+        create_empty_table::<Value>(table)?;
+
+        let created = table_info(table)?;
+
+        assert_eq!(created.records, 0);
+        assert_eq!(created.created, created.modified);
+        assert!(created.created.is_some());
+        assert_eq!(
+            (created.format_version, created.codec.as_str()),
+            (1, "json")
+        );
+
+        thread::sleep(Duration::from_millis(2));
+
+        append_records(table, json!({"name": "Ada"}))?;
+        create_index(table, "name", "name")?;
+
+        set_tag(table, "owner", "billing")?;
+        set_tag(table, "tier", "hot")?;
+
+        assert!(remove_tag(table, "tier")?);
+        assert!(!remove_tag(table, "tier")?);
+
+        let info = table_info(table)?;
+
+        assert_eq!(info.records, 1);
+        assert_eq!(info.created, created.created);
+        assert!(info.modified > created.modified);
+        assert!(info.size > created.size);
+        assert_eq!(info.tags.get("owner").map(String::as_str), Some("billing"));
+        assert_eq!(info.tags.len(), 1);
+
+        drop_table(table)?;
+
+        assert!(matches!(table_info(table), Err(Error::NoSuchTable(_))));
+        assert!(matches!(
+            set_tag(table, "owner", "billing"),
+            Err(Error::NoSuchTable(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Migration module.
+//!
+//! Every table records the version of the shape of its records, starting at 0. Migrations
+//! registered for a table each take its records from one version to the next. A table found at
+//! an older version than its migrations lead to is migrated the first time it is read, and
+//! written back, or ahead of time with `migrate_table`. Tables created while migrations are
+//! registered start out at the latest version. Migrating rebuilds the indexes of a table, but
+//! subscribers are not told, as the records are still the same ones.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, TableData, constraints, lock_for_upkeep, lock_writes, read_contents, reindex, schema,
+};
+
+type Migration = Arc<dyn Fn(Value) -> Result<Value> + Send + Sync>;
+
+static MIGRATIONS: Mutex<BTreeMap<String, BTreeMap<u32, Migration>>> = Mutex::new(BTreeMap::new());
+
+/// What migrating a table did, or in a dry run would do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    /// How many records were migrated.
+    pub migrated: usize,
+    /// The id of every record that could not be migrated, with the reason.
+    pub failures: Vec<(String, String)>,
+    /// The first version no migration is registered from, if any, in which case no record could
+    /// be migrated.
+    pub missing: Option<u32>,
+}
+
+/// Registers `migrate` as the way to take the records of `table` from `from_version` to the
+/// next version, replacing any migration registered for that version before.
+pub fn register_migration<F>(table: &str, from_version: u32, migrate: F)
+where
+    F: Fn(Value) -> Value + Send + Sync + 'static,
+{
+    register(
+        table,
+        from_version,
+        Arc::new(move |record| Ok(migrate(record))),
+    );
+}
+
+/// Like `register_migration`, but migrates each record by deserializing it as `Old` and
+/// converting it into `New`.
+pub fn register_typed_migration<Old, New>(table: &str, from_version: u32)
+where
+    Old: for<'a> Deserialize<'a>,
+    New: From<Old> + Serialize,
+{
+    register(
+        table,
+        from_version,
+        Arc::new(|record| {
+            let old: Old = serde_json::from_value(record)?;
+
+            Ok(serde_json::to_value(New::from(old))?)
+        }),
+    );
+}
+
+/// Migrates `table` to the latest version, returning what was done.
+///
+/// With `dry_run` nothing is written and the report lists every record that would fail, or the
+/// version a migration is missing from. Otherwise any failure leaves the table untouched and
+/// fails with `Error::Migration`. Indexes are rebuilt.
+pub fn migrate_table(table: &str, dry_run: bool) -> Result<MigrationReport> {
+    let _lock = lock_writes()?;
+
+    migrate(table, dry_run)
+}
+
+// Brings a table up to date as it is read, which may happen inside a transaction, where the
+// migrated table is just what the transaction goes on to read
+pub(crate) fn migrate_on_read(table: &str) -> Result<()> {
+    let _lock = lock_for_upkeep()?;
+
+    migrate(table, false).map(|_| ())
+}
+
+fn migrate(table: &str, dry_run: bool) -> Result<MigrationReport> {
+    let mut data: TableData<Value> = serde_json::from_str(&read_contents(table)?)?;
+
+    let from_version = data.schema_version;
+
+    let to_version = latest_version(table).max(from_version);
+
+    let mut report = MigrationReport {
+        from_version,
+        to_version,
+        migrated: 0,
+        failures: Vec::new(),
+        missing: None,
+    };
+
+    if from_version == to_version {
+        return Ok(report);
+    }
+
+    let steps = {
+        let migrations = lock_migrations();
+
+        let registered = migrations.get(table);
+
+        (from_version..to_version)
+            .map(|version| {
+                registered
+                    .and_then(|steps| steps.get(&version))
+                    .cloned()
+                    .ok_or(version)
+            })
+            .collect::<std::result::Result<Vec<Migration>, u32>>()
+    };
+
+    let steps = match steps {
+        Ok(steps) => steps,
+        Err(version) if dry_run => {
+            report.missing = Some(version);
+
+            return Ok(report);
+        }
+        Err(version) => {
+            return Err(Error::Migration {
+                table: table.to_string(),
+                version,
+                failures: Vec::new(),
+            });
+        }
+    };
+
+    let mut changes = vec![Change::Clear];
+
+    for (id, record) in data.records.iter_mut() {
+        let migrated = steps
+            .iter()
+            .try_fold(record.clone(), |record, step| step(record));
+
+        match migrated {
+            Ok(new) => {
+                *record = new.clone();
+
+                changes.push(Change::Insert {
+                    id: id.clone(),
+                    new,
+                });
+
+                report.migrated += 1;
+            }
+            Err(err) => report.failures.push((id.clone(), err.to_string())),
+        }
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    if !report.failures.is_empty() {
+        return Err(Error::Migration {
+            table: table.to_string(),
+            version: from_version,
+            failures: report.failures,
+        });
+    }
+
+    data.schema_version = to_version;
+
+    schema::check(table, &changes)?;
+
+    constraints::check(table, &data, &changes)?;
+
+    super::upgrade_table(table, &data)?;
+
+    reindex(table, &changes)?;
+
+    Ok(report)
+}
+
+// The version tables end up at once every migration registered for them has run
+pub(crate) fn latest_version(table: &str) -> u32 {
+    lock_migrations()
+        .get(table)
+        .and_then(|steps| steps.keys().next_back())
+        .map_or(0, |version| version + 1)
+}
+
+// Whether a table read from disk is older than its migrations, which is only worth finding out
+// for tables that have any
+pub(crate) fn outdated(table: &str, contents: &str) -> Result<bool> {
+    #[derive(Deserialize)]
+    struct Version {
+        #[serde(default)]
+        schema_version: u32,
+    }
+
+    let latest = latest_version(table);
+
+    if latest == 0 {
+        return Ok(false);
+    }
+
+    let version: Version = serde_json::from_str(contents)?;
+
+    Ok(version.schema_version < latest)
+}
+
+fn register(table: &str, from_version: u32, migration: Migration) {
+    lock_migrations()
+        .entry(table.to_string())
+        .or_default()
+        .insert(from_version, migration);
+}
+
+fn lock_migrations() -> MutexGuard<'static, BTreeMap<String, BTreeMap<u32, Migration>>> {
+    MIGRATIONS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_migration {
+    use super::*;
+    use crate::{
+        create_empty_table, create_index, drop_table, find_by_index, get_table, subscribe,
+        transaction,
+    };
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Point {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Place {
+        pub name: String,
+        pub at: Point,
+    }
+
+    impl From<Point> for Place {
+        fn from(at: Point) -> Place {
+            Place {
+                name: format!("{},{}", at.x, at.y),
+                at,
+            }
+        }
+    }
+
+    // Old tables were written with the coordinates under other names
+    fn rename_fields(mut record: Value) -> Value {
+        if let Some(object) = record.as_object_mut() {
+            if let Some(lat) = object.remove("lat") {
+                object.insert("x".to_string(), lat);
+            }
+
+            if let Some(lon) = object.remove("lon") {
+                object.insert("y".to_string(), lon);
+            }
+        }
+
+        record
+    }
+
+    fn store_old_table(table: &str, records: Value) -> Result<()> {
+        let data = json!({"table": table, "next_id": "2", "records": records});
+
+        super::super::upgrade_table(table, &data)
+    }
+
+    #[test]
+    fn can_migrate_lazily_on_read() -> Result<()> {
+        let table = "migration_lazy_test";
+
+        create_empty_table::<Value>(table)?;
+
+        store_old_table(
+            table,
+            json!({"0": {"lat": 1, "lon": 2}, "1": {"lat": 3, "lon": 4}}),
+        )?;
+
+        create_index(table, "name", "name")?;
+
+        register_migration(table, 0, rename_fields);
+        register_typed_migration::<Point, Place>(table, 1);
+
+        let events = Arc::new(AtomicUsize::new(0));
+
+        let counted = events.clone();
+
+        let subscription = subscribe(Some(table), move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let place: Place = transaction(|tx| tx.find(table, "1"))?;
+
+        assert_eq!(place.name, "3,4");
+
+        drop(subscription);
+
+        assert_eq!(events.load(Ordering::SeqCst), 0);
+
+        assert_eq!(get_table::<Place>(table)?.schema_version, 2);
+
+        let found = find_by_index::<Place, _>(table, "name", &"1,2")?;
+
+        assert_eq!(found.len(), 1);
+
+        drop_table(table)?;
+
+        create_empty_table::<Place>(table)?;
+
+        assert_eq!(get_table::<Place>(table)?.schema_version, 2);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_dry_run_and_refuse_failing_migrations() -> Result<()> {
+        let table = "migration_dry_run_test";
+
+        create_empty_table::<Value>(table)?;
+
+        store_old_table(table, json!({"0": {"x": 1, "y": 2}, "1": {"x": "three"}}))?;
+
+        assert_eq!(migrate_table(table, true)?.migrated, 0);
+
+        register_typed_migration::<Point, Place>(table, 1);
+
+        assert!(matches!(
+            migrate_table(table, false),
+            Err(Error::Migration { version: 0, .. })
+        ));
+
+        let report = migrate_table(table, true)?;
+
+        assert_eq!(report.missing, Some(0));
+        assert_eq!(report.migrated, 0);
+
+        register_migration(table, 0, |record| record);
+
+        let report = migrate_table(table, true)?;
+
+        assert_eq!((report.from_version, report.to_version), (0, 2));
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, "1");
+
+        assert!(matches!(
+            migrate_table(table, false),
+            Err(Error::Migration { version: 0, .. })
+        ));
+
+        assert!(super::super::read_contents(table)?.contains("three"));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Namespace module.
+//!
+//! A namespace is a subdirectory of the database holding tables of its own, so tables of
+//! different services can share names without mixing. A table in a namespace is addressed by
+//! its qualified name, `namespace/table`, which every table function accepts. Unqualified names
+//! keep addressing the tables at the top of the database.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::errors::{Error, Result};
+use super::{DB_PATH, create_db_dir, drop_table, lock_writes};
+
+/// Joins a namespace and a table name into the qualified name of the table.
+pub fn qualified(namespace: &str, table: &str) -> String {
+    format!("{}/{}", namespace, table)
+}
+
+/// Creates the namespace `namespace`, unless it already exists.
+pub fn create_namespace(namespace: &str) -> Result<()> {
+    let path = namespace_path(namespace)?;
+
+    create_db_dir()?;
+
+    match fs::create_dir(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result.map_err(Error::from),
+    }
+}
+
+pub fn list_namespaces() -> io::Result<Vec<String>> {
+    let mut namespaces = Vec::new();
+
+    if !Path::new(DB_PATH).exists() {
+        return Ok(namespaces);
+    }
+
+    for entry in fs::read_dir(DB_PATH)? {
+        let entry = entry?;
+
+        if let Some(name) = entry.file_name().to_str()
+            && !name.starts_with('.')
+            && entry.file_type()?.is_dir()
+        {
+            namespaces.push(name.to_string());
+        }
+    }
+
+    Ok(namespaces)
+}
+
+/// Lists the qualified names of the tables in `namespace`.
+pub fn list_tables_in(namespace: &str) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(namespace_path(namespace)?) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::NoSuchNamespace(namespace.to_string()));
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    let mut tables = Vec::new();
+
+    for entry in entries {
+        let entry = entry?;
+
+        if let Some(name) = entry.file_name().to_str()
+            && !name.starts_with('.')
+            && entry.file_type()?.is_file()
+        {
+            tables.push(qualified(namespace, name));
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Drops every table in `namespace`, as by `drop_table`, and then the namespace itself.
+pub fn drop_namespace(namespace: &str) -> Result<()> {
+    let path = namespace_path(namespace)?;
+    let _lock = lock_writes()?;
+
+    let canonical = match path.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::NoSuchNamespace(namespace.to_string()));
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    // Never drop anything but the tables of a directory right under the database
+    if canonical.parent() != Some(&Path::new(DB_PATH).canonicalize()?) {
+        return Err(invalid_name(namespace));
+    }
+
+    for table in list_tables_in(namespace)? {
+        drop_table(&table)?;
+    }
+
+    fs::remove_dir_all(path)?;
+
+    Ok(())
+}
+
+// Fails unless the namespace a table is to be written into exists
+pub(crate) fn check_namespace(table: &str) -> Result<()> {
+    match table.rsplit_once('/') {
+        Some((namespace, _)) if !namespace_path(namespace)?.is_dir() => {
+            Err(Error::NoSuchNamespace(namespace.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The directory of a namespace, once its name is known to stay right under the database
+fn namespace_path(namespace: &str) -> Result<PathBuf> {
+    if namespace.is_empty() || namespace.starts_with('.') || namespace.contains(['/', '\\']) {
+        return Err(invalid_name(namespace));
+    }
+
+    Ok(Path::new(DB_PATH).join(namespace))
+}
+
+fn invalid_name(namespace: &str) -> Error {
+    Error::InvalidInput(format!("\"{}\" is not a valid namespace name", namespace))
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_namespace {
+    use super::*;
+    use crate::{
+        append_records, create_empty_table, create_index, find, find_by_index, list_tables,
+        rename_table, table_exists,
+    };
+    use serde_json::{Value, json};
+
+    #[test]
+    fn can_keep_tables_apart() -> Result<()> {
+        let (billing, shipping) = ("ns_billing_test", "ns_shipping_test");
+
+        create_namespace(billing)?;
+        create_namespace(shipping)?;
+        create_namespace(billing)?;
+
+        let invoices = qualified(billing, "invoices");
+
+        create_empty_table::<Value>(&invoices)?;
+        create_empty_table::<Value>(&qualified(shipping, "invoices"))?;
+
+        append_records(&invoices, json!({"total": 12}))?;
+        create_index(&invoices, "total", "total")?;
+
+        assert_eq!(find::<Value>(&invoices, "0")?, json!({"total": 12}));
+        assert_eq!(find_by_index::<Value, _>(&invoices, "total", &12)?.len(), 1);
+
+        assert!(matches!(
+            find::<Value>(&qualified(shipping, "invoices"), "0"),
+            Err(Error::NoSuchKey)
+        ));
+
+        assert!(list_namespaces()?.contains(&billing.to_string()));
+        assert!(!list_tables()?.contains(&billing.to_string()));
+        assert!(!table_exists(billing));
+
+        rename_table(&invoices, &qualified(billing, "paid"))?;
+
+        assert_eq!(list_tables_in(billing)?, vec![qualified(billing, "paid")]);
+
+        assert!(matches!(
+            create_empty_table::<Value>("ns_missing_test/invoices"),
+            Err(Error::NoSuchNamespace(_))
+        ));
+
+        for name in ["..", "", "a/b"] {
+            assert!(matches!(
+                create_namespace(name),
+                Err(Error::InvalidInput(_))
+            ));
+            assert!(matches!(list_tables_in(name), Err(Error::InvalidInput(_))));
+            assert!(matches!(drop_namespace(name), Err(Error::InvalidInput(_))));
+        }
+
+        drop_namespace(billing)?;
+        drop_namespace(shipping)?;
+
+        assert!(!list_namespaces()?.contains(&billing.to_string()));
+        assert!(matches!(
+            drop_namespace(billing),
+            Err(Error::NoSuchNamespace(_))
+        ));
+
+        Ok(())
+    }
+}
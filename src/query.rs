@@ -0,0 +1,302 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Query builder module.
+
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::errors::Result;
+use super::{get_table_records, raw_records, read_table};
+
+type Filter<'q, T> = Box<dyn Fn(&T) -> bool + 'q>;
+type Comparator<'q, T> = Box<dyn Fn(&T, &T) -> Ordering + 'q>;
+
+/// A lazily built query over the records of one table.
+///
+/// Nothing is read until one of `fetch`, `first`, `count` or `ids_only` is called. Records come
+/// back in id order unless `sort_by_key` is given, in which case equal keys keep id order.
+pub struct Query<'q, T> {
+    table: String,
+    filters: Vec<Filter<'q, T>>,
+    sort: Option<Comparator<'q, T>>,
+    reverse: bool,
+    offset: usize,
+    limit: Option<usize>,
+    marker: PhantomData<T>,
+}
+
+/// Starts a query over the records of `table`.
+pub fn query<'q, T>(table: &str) -> Query<'q, T>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    Query {
+        table: table.to_string(),
+        filters: Vec::new(),
+        sort: None,
+        reverse: false,
+        offset: 0,
+        limit: None,
+        marker: PhantomData,
+    }
+}
+
+impl<'q, T> Query<'q, T>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    /// Keeps only the records matching `predicate`. Several filters must all match.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&T) -> bool + 'q,
+    {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Orders the records by the key `f` extracts from them.
+    pub fn sort_by_key<K, F>(mut self, f: F) -> Self
+    where
+        K: Ord,
+        F: Fn(&T) -> K + 'q,
+    {
+        self.sort = Some(Box::new(move |a, b| f(a).cmp(&f(b))));
+        self
+    }
+
+    /// Reverses the order, before `offset` and `limit` are applied.
+    pub fn reverse(mut self) -> Self {
+        self.reverse = !self.reverse;
+        self
+    }
+
+    /// Skips the first `n` records.
+    pub fn offset(mut self, n: usize) -> Self {
+        self.offset = n;
+        self
+    }
+
+    /// Returns at most `n` records.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Runs the query and returns the matching records in order.
+    pub fn fetch(self) -> Result<Vec<(String, T)>> {
+        let mut records: Vec<(String, T)> = get_table_records::<T>(&self.table)?
+            .into_iter()
+            .filter(|(_, record)| self.filters.iter().all(|filter| filter(record)))
+            .collect();
+
+        if let Some(ref sort) = self.sort {
+            records.sort_by(|a, b| sort(&a.1, &b.1));
+        }
+
+        if self.reverse {
+            records.reverse();
+        }
+
+        let limit = self.limit.unwrap_or(usize::MAX);
+
+        Ok(records.into_iter().skip(self.offset).take(limit).collect())
+    }
+
+    /// Runs the query and returns only its first record.
+    pub fn first(self) -> Result<Option<(String, T)>> {
+        Ok(self.limit(1).fetch()?.into_iter().next())
+    }
+
+    /// Runs the query and returns how many records it matched.
+    pub fn count(self) -> Result<usize> {
+        Ok(self.fetch()?.len())
+    }
+
+    /// Runs the query and returns only the ids of the matching records.
+    pub fn ids_only(self) -> Result<Vec<String>> {
+        Ok(self.fetch()?.into_iter().map(|(id, _)| id).collect())
+    }
+}
+
+/// An opaque position in a table's id order, handed out by `page` to fetch the page after it.
+///
+/// Cursors (de)serialize, so they can be handed to clients and read back on the next request.
+/// They point between ids rather than at an offset, so records inserted or deleted between two
+/// requests never shift or repeat the records of later pages.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cursor {
+    after: String,
+}
+
+/// One page of records, in id order, and the cursor for the next page if there is one.
+#[derive(Debug, PartialEq)]
+pub struct Page<T> {
+    pub records: Vec<(String, T)>,
+    pub next: Option<Cursor>,
+}
+
+/// Returns up to `limit` records following `after`, or from the start of the table when `after`
+/// is `None`.
+///
+/// Only the records on the page, and the one after it, are deserialized.
+pub fn page<T>(table: &str, after: Option<&Cursor>, limit: usize) -> Result<Page<T>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let contents = read_table(table)?;
+
+    let raw = raw_records(&contents)?;
+
+    let start = match after {
+        Some(cursor) => Bound::Excluded(cursor.after.as_str()),
+        None => Bound::Unbounded,
+    };
+
+    let mut records = raw
+        .range((start, Bound::Unbounded))
+        .take(limit.saturating_add(1))
+        .map(|(id, record)| Ok((id.clone(), serde_json::from_str(record.get())?)))
+        .collect::<Result<Vec<(String, T)>>>()?;
+
+    let more = records.len() > limit;
+
+    records.truncate(limit);
+
+    let next = match records.last() {
+        Some((id, _)) if more => Some(Cursor { after: id.clone() }),
+        _ => None,
+    };
+
+    Ok(Page { records, next })
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_query {
+    use super::*;
+    use crate::{batch_insert, create_empty_table, drop_table};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Coordinates {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    fn create_query_table(table: &str) -> Result<()> {
+        create_empty_table::<Coordinates>(table)?;
+
+        let records = (0..12).map(|n| Coordinates { x: n, y: n % 3 }).collect();
+
+        batch_insert(table, records)
+    }
+
+    #[test]
+    fn can_order_by_numeric_id() -> Result<()> {
+        let table = "query_order_test";
+
+        create_query_table(table)?;
+
+        let ids = query::<Coordinates>(table).ids_only()?;
+
+        let expected: Vec<String> = (0..12).map(|n| n.to_string()).collect();
+
+        assert_eq!(ids, expected);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_page_with_a_cursor() -> Result<()> {
+        let table = "query_cursor_test";
+
+        create_query_table(table)?;
+
+        let first = page::<Coordinates>(table, None, 5)?;
+
+        let ids: Vec<&str> = first.records.iter().map(|(id, _)| id.as_str()).collect();
+
+        assert_eq!(ids, vec!["0", "1", "2", "3", "4"]);
+
+        let cursor = first.next.expect("a second page");
+
+        let json = serde_json::to_string(&cursor)?;
+
+        let cursor: Cursor = serde_json::from_str(&json)?;
+
+        crate::append_records(table, Coordinates { x: 12, y: 0 })?;
+
+        crate::delete::<Coordinates>(table, "1")?;
+
+        let second = page::<Coordinates>(table, Some(&cursor), 5)?;
+
+        let ids: Vec<&str> = second.records.iter().map(|(id, _)| id.as_str()).collect();
+
+        assert_eq!(ids, vec!["5", "6", "7", "8", "9"]);
+
+        let third = page::<Coordinates>(table, second.next.as_ref(), 5)?;
+
+        let ids: Vec<&str> = third.records.iter().map(|(id, _)| id.as_str()).collect();
+
+        assert_eq!(ids, vec!["10", "11", "12"]);
+
+        assert_eq!(third.next, None);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_filter_sort_and_page() -> Result<()> {
+        let table = "query_page_test";
+
+        create_query_table(table)?;
+
+        let records = query::<Coordinates>(table)
+            .filter(|coord| coord.y == 0)
+            .sort_by_key(|coord| coord.x)
+            .reverse()
+            .offset(1)
+            .limit(2)
+            .fetch()?;
+
+        assert_eq!(
+            records,
+            vec![
+                ("6".to_string(), Coordinates { x: 6, y: 0 }),
+                ("3".to_string(), Coordinates { x: 3, y: 0 }),
+            ]
+        );
+
+        let first = query::<Coordinates>(table)
+            .filter(|coord| coord.y == 2)
+            .first()?;
+
+        assert_eq!(first, Some(("2".to_string(), Coordinates { x: 2, y: 2 })));
+
+        let count = query::<Coordinates>(table)
+            .filter(|coord| coord.y == 1)
+            .filter(|coord| coord.x > 4)
+            .count()?;
+
+        assert_eq!(count, 2);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Ordered record storage module.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+/// Orders record ids numerically when both are numbers, so `"10"` follows `"9"`.
+///
+/// Numeric ids sort before any other id, and everything else falls back to plain string order.
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    fn numeric(id: &str) -> Option<&str> {
+        if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) {
+            Some(id.trim_start_matches('0'))
+        } else {
+            None
+        }
+    }
+
+    match (numeric(a), numeric(b)) {
+        (Some(x), Some(y)) => x.len().cmp(&y.len()).then(x.cmp(y)).then(a.cmp(b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+// A record id which orders itself with `compare_ids`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RecordId(pub(crate) String);
+
+impl Ord for RecordId {
+    fn cmp(&self, other: &RecordId) -> Ordering {
+        compare_ids(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for RecordId {
+    fn partial_cmp(&self, other: &RecordId) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The records of a table, keyed by id and always kept in id order.
+///
+/// Iterating and serializing both follow `compare_ids`, so two tables with the same contents are
+/// written out byte for byte the same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Records<T> {
+    map: BTreeMap<RecordId, T>,
+}
+
+impl<T> Records<T> {
+    pub fn new() -> Records<T> {
+        Records {
+            map: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains_key(&self, id: &str) -> bool {
+        self.map.contains_key(&RecordId(id.to_string()))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&T> {
+        self.map.get(&RecordId(id.to_string()))
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut T> {
+        self.map.get_mut(&RecordId(id.to_string()))
+    }
+
+    pub fn insert(&mut self, id: String, record: T) -> Option<T> {
+        self.map.insert(RecordId(id), record)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<T> {
+        self.map.remove(&RecordId(id.to_string()))
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            inner: self.map.range(..),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut {
+            inner: self.map.iter_mut(),
+        }
+    }
+
+    /// Iterates over the records whose ids fall within `ids`, in id order.
+    pub fn range<'r, R>(&self, ids: R) -> Iter<'_, T>
+    where
+        R: RangeBounds<&'r str>,
+    {
+        fn owned(bound: Bound<&&str>) -> Bound<RecordId> {
+            match bound {
+                Bound::Included(id) => Bound::Included(RecordId(id.to_string())),
+                Bound::Excluded(id) => Bound::Excluded(RecordId(id.to_string())),
+                Bound::Unbounded => Bound::Unbounded,
+            }
+        }
+
+        let start = owned(ids.start_bound());
+        let end = owned(ids.end_bound());
+
+        // BTreeMap::range panics on inverted or doubly excluded empty ranges
+        let empty = match (&start, &end) {
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+                s > e
+            }
+            _ => false,
+        };
+
+        if empty {
+            return Iter {
+                inner: btree_map::Range::default(),
+            };
+        }
+
+        Iter {
+            inner: self.map.range((start, end)),
+        }
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &String> {
+        self.map.keys().map(|id| &id.0)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.map.values()
+    }
+}
+
+impl<T> Default for Records<T> {
+    fn default() -> Records<T> {
+        Records::new()
+    }
+}
+
+impl<T> FromIterator<(String, T)> for Records<T> {
+    fn from_iter<I: IntoIterator<Item = (String, T)>>(iter: I) -> Records<T> {
+        Records {
+            map: iter.into_iter().map(|(id, t)| (RecordId(id), t)).collect(),
+        }
+    }
+}
+
+impl<T> Extend<(String, T)> for Records<T> {
+    fn extend<I: IntoIterator<Item = (String, T)>>(&mut self, iter: I) {
+        self.map
+            .extend(iter.into_iter().map(|(id, t)| (RecordId(id), t)))
+    }
+}
+
+// Iterators **************************************************************************************
+
+/// An iterator over the records of a `Records`, in id order.
+pub struct Iter<'a, T> {
+    inner: btree_map::Range<'a, RecordId, T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a String, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(id, t)| (&id.0, t))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<T> DoubleEndedIterator for Iter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(id, t)| (&id.0, t))
+    }
+}
+
+/// A mutable iterator over the records of a `Records`, in id order.
+pub struct IterMut<'a, T> {
+    inner: btree_map::IterMut<'a, RecordId, T>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (&'a String, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(id, t)| (&id.0, t))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<T> DoubleEndedIterator for IterMut<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(id, t)| (&id.0, t))
+    }
+}
+
+/// An owning iterator over the records of a `Records`, in id order.
+pub struct IntoIter<T> {
+    inner: btree_map::IntoIter<RecordId, T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = (String, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(id, t)| (id.0, t))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<T> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(id, t)| (id.0, t))
+    }
+}
+
+impl<T> IntoIterator for Records<T> {
+    type Item = (String, T);
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            inner: self.map.into_iter(),
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Records<T> {
+    type Item = (&'a String, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut Records<T> {
+    type Item = (&'a String, &'a mut T);
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> IterMut<'a, T> {
+        self.iter_mut()
+    }
+}
+
+// (De)serialization ******************************************************************************
+
+impl<T: Serialize> Serialize for Records<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+
+        for (id, record) in self {
+            map.serialize_entry(id, record)?;
+        }
+
+        map.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Records<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Records<T>, D::Error> {
+        struct RecordsVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for RecordsVisitor<T> {
+            type Value = Records<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of record ids to records")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Records<T>, A::Error> {
+                let mut records = Records::new();
+
+                while let Some((id, record)) = access.next_entry::<String, T>()? {
+                    records.insert(id, record);
+                }
+
+                Ok(records)
+            }
+        }
+
+        deserializer.deserialize_map(RecordsVisitor(PhantomData))
+    }
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_records {
+    use super::*;
+
+    #[test]
+    fn can_order_ids_numerically() {
+        let mut ids = vec!["b", "10", "9", "a", "010", "0", "100"];
+
+        ids.sort_by(|a, b| compare_ids(a, b));
+
+        assert_eq!(ids, vec!["0", "9", "010", "10", "100", "a", "b"]);
+    }
+
+    #[test]
+    fn can_serialize_in_id_order() -> serde_json::Result<()> {
+        let records: Records<i32> = serde_json::from_str("{\"10\":10,\"x\":0,\"9\":9,\"1\":1}")?;
+
+        assert_eq!(
+            serde_json::to_string(&records)?,
+            "{\"1\":1,\"9\":9,\"10\":10,\"x\":0}"
+        );
+
+        let ids: Vec<&String> = records.keys().collect();
+
+        assert_eq!(ids, vec!["1", "9", "10", "x"]);
+
+        let ids: Vec<&String> = records.range("2".."x").map(|(id, _)| id).collect();
+
+        assert_eq!(ids, vec!["9", "10"]);
+
+        assert_eq!(records.range("10".."9").count(), 0);
+
+        Ok(())
+    }
+}
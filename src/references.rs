@@ -0,0 +1,733 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Cross-table reference module.
+//!
+//! A reference declares that the value at some path of every record in one table is the id of a
+//! record in another table. References span tables, so they are all kept in one hidden file at
+//! the root of the database rather than next to any one table.
+//!
+//! Writes to a table with references are committed as transactions, so a delete and every delete
+//! or null it cascades to are written together, or not at all.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::transaction::{Transaction, transaction};
+use super::{
+    Change, DB_PATH, TableData, get_table, get_table_ids, json_pointer, lock_writes, table_exists,
+};
+
+/// What happens to the records referring to a record which gets deleted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OnDelete {
+    /// The delete fails with `Error::ReferenceViolation`.
+    Restrict,
+    /// The referring records are deleted too.
+    Cascade,
+    /// The reference in each referring record is set to `null`.
+    SetNull,
+}
+
+// Guards the read-modify-write of the declarations file
+static DECLARATIONS: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Reference {
+    table: String,
+    path: String,
+    referenced_table: String,
+    on_delete: OnDelete,
+}
+
+/// Declares that the value each record of `table` holds at `path` is the id of a record in
+/// `referenced_table`.
+///
+/// `path` is either a top-level field name or a JSON pointer. Ids may be stored as strings or
+/// numbers, and records missing the value or holding `null` refer to nothing. Every insert and
+/// update of `table` is checked against `referenced_table`, and `on_delete` decides what deleting
+/// a referenced record does.
+///
+/// Fails with `Error::ReferenceViolation` if a record already stored refers to a missing id. A
+/// table referring to itself only supports `OnDelete::Restrict`, and any other `on_delete` is
+/// refused with `Error::SelfReference`.
+pub fn add_reference(
+    table: &str,
+    path: &str,
+    referenced_table: &str,
+    on_delete: OnDelete,
+) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    if !table_exists(table) {
+        return Err(Error::NoSuchTable(table.to_string()));
+    }
+
+    let reference = Reference {
+        table: table.to_string(),
+        path: json_pointer(path),
+        referenced_table: referenced_table.to_string(),
+        on_delete,
+    };
+
+    if table == referenced_table && on_delete != OnDelete::Restrict {
+        return Err(Error::SelfReference {
+            table: reference.table,
+            field: reference.path,
+        });
+    }
+
+    let data = get_table::<Value>(table)?;
+
+    let ids = match table == referenced_table {
+        true => live_ids(&data),
+        false => record_ids(referenced_table)?,
+    };
+
+    for (id, record) in data.records.iter() {
+        if data.is_live(id) {
+            check_exists(&reference, record, &ids)?;
+        }
+    }
+
+    let _guard = lock_declarations();
+
+    let mut references = read_references()?;
+
+    references.retain(|other| other.table != table || other.path != reference.path);
+    references.push(reference);
+
+    write_references(&references)
+}
+
+/// Removes the reference declared on `path` of `table`.
+pub fn drop_reference(table: &str, path: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let path = json_pointer(path);
+
+    let _guard = lock_declarations();
+
+    let mut references = read_references()?;
+
+    let count = references.len();
+
+    references.retain(|reference| reference.table != table || reference.path != path);
+
+    if references.len() == count {
+        return Err(Error::NoSuchConstraint(path));
+    }
+
+    write_references(&references)
+}
+
+// Whether any reference starts or ends at the table, in which case writes to it are committed
+// through a transaction, along with whatever they cascade to
+pub(crate) fn involved(table: &str) -> Result<bool> {
+    if !references_path().exists() {
+        return Ok(false);
+    }
+
+    Ok(read_references()?
+        .iter()
+        .any(|reference| reference.table == table || reference.referenced_table == table))
+}
+
+// Stages the deletes and nulls cascading from the records that the changes of `table` staged
+// since `from` took away. Nothing is checked yet, as later cascades may still change the picture.
+pub(crate) fn cascade(tx: &mut Transaction, table: &str, from: usize) -> Result<()> {
+    if !references_path().exists() {
+        return Ok(());
+    }
+
+    let references = read_references()?;
+
+    let cascading: Vec<&Reference> = references
+        .iter()
+        .filter(|r| r.referenced_table == table && r.on_delete != OnDelete::Restrict)
+        .collect();
+
+    if cascading.is_empty() {
+        return Ok(());
+    }
+
+    let deleted = deleted_ids(tx, table, from)?;
+
+    if deleted.is_empty() {
+        return Ok(());
+    }
+
+    for reference in cascading {
+        if !tx.staged.contains_key(&reference.table) && !table_exists(&reference.table) {
+            continue;
+        }
+
+        let staged = tx.stage(&reference.table)?;
+
+        for id in referring_ids(reference, &staged.data, &deleted) {
+            if reference.on_delete == OnDelete::Cascade {
+                if let Some(old) = staged.data.records.remove(&id) {
+                    staged.changes.push(Change::Delete { id, old });
+                }
+
+                continue;
+            }
+
+            if let Some(record) = staged.data.records.get_mut(&id) {
+                let old = record.clone();
+
+                if let Some(value) = record.pointer_mut(&reference.path) {
+                    *value = Value::Null;
+                }
+
+                let new = record.clone();
+
+                staged.changes.push(Change::Update { id, old, new });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Checks every table a transaction writes, as it will be once every cascade is done, against
+// every reference from or to it
+pub(crate) fn check(tx: &mut Transaction) -> Result<()> {
+    if !references_path().exists() {
+        return Ok(());
+    }
+
+    let references = read_references()?;
+
+    let written: Vec<String> = tx
+        .staged
+        .iter()
+        .filter(|(_, staged)| !staged.changes.is_empty())
+        .map(|(table, _)| table.clone())
+        .collect();
+
+    for reference in &references {
+        if !written.contains(&reference.table) && !written.contains(&reference.referenced_table) {
+            continue;
+        }
+
+        for other in [&reference.table, &reference.referenced_table] {
+            if !tx.staged.contains_key(other) && table_exists(other) {
+                tx.stage(other)?;
+            }
+        }
+    }
+
+    for table in &written {
+        let data = &tx.staged[table].data;
+
+        for reference in references.iter().filter(|r| &r.table == table) {
+            let ids: BTreeSet<String> = match tx.staged.get(&reference.referenced_table) {
+                Some(referenced) => live_ids(&referenced.data),
+                None => BTreeSet::new(),
+            };
+
+            // A table referring to itself may have had any record pulled from under the others
+            if &reference.referenced_table == table {
+                for (id, record) in data.records.iter() {
+                    if data.is_live(id) {
+                        check_exists(reference, record, &ids)?;
+                    }
+                }
+
+                continue;
+            }
+
+            for change in &tx.staged[table].changes {
+                if let Change::Insert { id, .. } | Change::Update { id, .. } = change
+                    && let Some(record) = data.records.get(id)
+                {
+                    check_exists(reference, record, &ids)?;
+                }
+            }
+        }
+
+        let restricting: Vec<&Reference> = references
+            .iter()
+            .filter(|r| &r.referenced_table == table && r.on_delete == OnDelete::Restrict)
+            .collect();
+
+        if restricting.is_empty() {
+            continue;
+        }
+
+        let deleted = deleted_ids(tx, table, 0)?;
+
+        for reference in restricting {
+            let referring = match tx.staged.get(&reference.table) {
+                Some(referring) => &referring.data,
+                None => continue,
+            };
+
+            let restricted = referring_ids(reference, referring, &deleted)
+                .first()
+                .and_then(|id| referring.records.get(id));
+
+            if let Some(record) = restricted {
+                return Err(violation(reference, record));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Lets go of every record referring to a table about to be dropped, which to them is the same as
+// clearing it
+pub(crate) fn enforce_drop(table: &str) -> Result<()> {
+    if !references_path().exists() {
+        return Ok(());
+    }
+
+    if read_references()?
+        .iter()
+        .any(|reference| reference.referenced_table == table && reference.table != table)
+    {
+        transaction(|tx| tx.clear_table(table))?;
+    }
+
+    let _guard = lock_declarations();
+
+    let mut references = read_references()?;
+
+    references.retain(|reference| reference.table != table);
+
+    write_references(&references)
+}
+
+// Points every reference from or to a renamed table at its new name
+pub(crate) fn enforce_rename(from: &str, to: &str) -> Result<()> {
+    if !references_path().exists() {
+        return Ok(());
+    }
+
+    let _guard = lock_declarations();
+
+    let mut references = read_references()?;
+
+    for reference in &mut references {
+        if reference.table == from {
+            reference.table = to.to_string();
+        }
+
+        if reference.referenced_table == from {
+            reference.referenced_table = to.to_string();
+        }
+    }
+
+    write_references(&references)
+}
+
+// Replaces the references from restored tables with those found in the declarations file of
+// the backup they came from, keeping every other
+pub(crate) fn merge_restored(restored: &Path, tables: &BTreeSet<String>) -> Result<()> {
+    let _guard = lock_declarations();
+
+    let mut references = read_references()?;
+
+    references.retain(|reference| !tables.contains(&reference.table));
+
+    match fs::File::open(restored) {
+        Ok(file) => {
+            let backed_up: Vec<Reference> = serde_json::from_reader(io::BufReader::new(file))?;
+
+            references.extend(
+                backed_up
+                    .into_iter()
+                    .filter(|reference| tables.contains(&reference.table)),
+            );
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(Error::Io(err)),
+    }
+
+    write_references(&references)
+}
+
+// The ids the changes of `table` staged since `from` took away, and which are still gone
+fn deleted_ids(tx: &Transaction, table: &str, from: usize) -> Result<BTreeSet<String>> {
+    let staged = &tx.staged[table];
+
+    let mut deleted = BTreeSet::new();
+
+    for change in &staged.changes[from..] {
+        match change {
+            Change::Delete { id, .. } => {
+                deleted.insert(id.clone());
+            }
+            Change::Clear => deleted.extend(record_ids(table)?),
+            _ => {}
+        }
+    }
+
+    deleted.retain(|id| !staged.data.records.contains_key(id));
+
+    Ok(deleted)
+}
+
+// The live records of `data` which refer to a deleted id. Expired records no longer refer to
+// anything, so they neither restrict a delete nor have it cascade to them.
+fn referring_ids(
+    reference: &Reference,
+    data: &TableData<Value>,
+    deleted: &BTreeSet<String>,
+) -> Vec<String> {
+    data.records
+        .iter()
+        .filter(|(id, _)| data.is_live(id))
+        .filter(|(_, record)| match referenced_id(reference, record) {
+            Some(id) => deleted.contains(&id),
+            None => false,
+        })
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+fn check_exists(reference: &Reference, record: &Value, ids: &BTreeSet<String>) -> Result<()> {
+    match record.pointer(&reference.path) {
+        None | Some(Value::Null) => Ok(()),
+        Some(_) => match referenced_id(reference, record) {
+            Some(ref id) if ids.contains(id) => Ok(()),
+            _ => Err(violation(reference, record)),
+        },
+    }
+}
+
+fn violation(reference: &Reference, record: &Value) -> Error {
+    let id = match referenced_id(reference, record) {
+        Some(id) => id,
+        None => record
+            .pointer(&reference.path)
+            .unwrap_or(&Value::Null)
+            .to_string(),
+    };
+
+    Error::ReferenceViolation {
+        table: reference.table.clone(),
+        referenced_table: reference.referenced_table.clone(),
+        id,
+    }
+}
+
+fn referenced_id(reference: &Reference, record: &Value) -> Option<String> {
+    match record.pointer(&reference.path) {
+        Some(Value::String(id)) => Some(id.clone()),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+fn live_ids(data: &TableData<Value>) -> BTreeSet<String> {
+    data.records
+        .keys()
+        .filter(|id| data.is_live(id))
+        .cloned()
+        .collect()
+}
+
+fn record_ids(table: &str) -> Result<BTreeSet<String>> {
+    match get_table_ids(table) {
+        Ok(ids) => Ok(ids.keys().cloned().collect()),
+        Err(Error::NoSuchTable(_)) => Ok(BTreeSet::new()),
+        Err(err) => Err(err),
+    }
+}
+
+fn lock_declarations() -> MutexGuard<'static, ()> {
+    DECLARATIONS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn references_path() -> PathBuf {
+    Path::new(DB_PATH).join(".references")
+}
+
+fn read_references() -> Result<Vec<Reference>> {
+    let file = match fs::File::open(references_path()) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_references(references: &Vec<Reference>) -> Result<()> {
+    if references.is_empty() {
+        return match fs::remove_file(references_path()) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map_err(Error::from),
+        };
+    }
+
+    super::write_json(&references_path(), references)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_references {
+    use super::*;
+    use crate::{
+        Hooks, append_records, append_records_with_ttl, clear_table, create_empty_table, delete,
+        drop_table, find, get_table_records, remove_hooks, set_hooks, update_record,
+    };
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Customer {
+        pub name: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Order {
+        pub customer: Option<String>,
+    }
+
+    fn order(customer: &str) -> Order {
+        Order {
+            customer: Some(customer.to_string()),
+        }
+    }
+
+    fn create_customers(customers: &str) -> Result<()> {
+        create_empty_table::<Customer>(customers)?;
+
+        for name in ["ada", "bob"] {
+            append_records(
+                customers,
+                Customer {
+                    name: name.to_string(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn is_violation(result: Result<()>, table: &str, referenced_table: &str) -> bool {
+        matches!(
+            result,
+            Err(Error::ReferenceViolation { table: ref t, referenced_table: ref r, .. })
+                if t == table && r == referenced_table
+        )
+    }
+
+    #[test]
+    fn can_check_inserts_and_updates() -> Result<()> {
+        let (customers, orders) = ("ref_insert_customers", "ref_insert_orders");
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+
+        add_reference(orders, "customer", customers, OnDelete::Restrict)?;
+
+        let itself = add_reference(orders, "customer", orders, OnDelete::Cascade);
+
+        assert!(matches!(
+            itself,
+            Err(Error::SelfReference { ref table, ref field })
+                if table == orders && field == "/customer"
+        ));
+
+        append_records(orders, order("1"))?;
+        append_records(orders, Order { customer: None })?;
+
+        let missing = append_records(orders, order("7"));
+
+        assert!(is_violation(missing, orders, customers));
+
+        let missing = update_record(orders, "1", order("7"));
+
+        assert!(is_violation(missing, orders, customers));
+
+        let deleted = delete::<Customer>(customers, "1").map(|_| ());
+
+        assert!(is_violation(deleted, orders, customers));
+
+        let dropped = drop_table(customers).map_err(Error::from);
+
+        assert!(is_violation(dropped, orders, customers));
+
+        drop_reference(orders, "/customer")?;
+
+        delete::<Customer>(customers, "1")?;
+
+        drop_table(orders)?;
+        drop_table(customers)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_references_to_expired_records() -> Result<()> {
+        let (customers, orders) = ("ref_expired_customers", "ref_expired_orders");
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+
+        append_records_with_ttl(
+            customers,
+            Customer {
+                name: "cy".to_string(),
+            },
+            Duration::ZERO,
+        )?;
+
+        add_reference(orders, "customer", customers, OnDelete::Restrict)?;
+
+        let expired = append_records(orders, order("2"));
+
+        assert!(is_violation(expired, orders, customers));
+
+        drop_table(orders)?;
+        drop_table(customers)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_delete_records_only_expired_records_refer_to() -> Result<()> {
+        let (customers, orders) = ("ref_unexpired_customers", "ref_unexpired_orders");
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+
+        add_reference(orders, "customer", customers, OnDelete::Restrict)?;
+
+        append_records(orders, order("1"))?;
+        append_records_with_ttl(orders, order("0"), Duration::ZERO)?;
+
+        delete::<Customer>(customers, "0")?;
+
+        let deleted = delete::<Customer>(customers, "1").map(|_| ());
+
+        assert!(is_violation(deleted, orders, customers));
+
+        drop_table(orders)?;
+        drop_table(customers)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_cascade_deletes() -> Result<()> {
+        let (customers, orders) = ("ref_cascade_customers", "ref_cascade_orders");
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+
+        add_reference(orders, "customer", customers, OnDelete::Cascade)?;
+
+        append_records(orders, order("0"))?;
+        append_records(orders, order("1"))?;
+        append_records(orders, order("0"))?;
+
+        delete::<Customer>(customers, "0")?;
+
+        let ids: Vec<String> = get_table_records::<Order>(orders)?
+            .keys()
+            .cloned()
+            .collect();
+
+        assert_eq!(ids, vec!["1"]);
+
+        clear_table::<Customer>(customers)?;
+
+        assert!(get_table_records::<Order>(orders)?.is_empty());
+
+        drop_table(orders)?;
+
+        append_records(
+            customers,
+            Customer {
+                name: "cy".to_string(),
+            },
+        )?;
+
+        drop_table(customers)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_a_cascade_part_way_through() -> Result<()> {
+        let (customers, orders, notes) = (
+            "ref_partial_customers",
+            "ref_partial_orders",
+            "ref_partial_notes",
+        );
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+        create_empty_table::<Order>(notes)?;
+
+        add_reference(orders, "customer", customers, OnDelete::Cascade)?;
+        add_reference(notes, "customer", customers, OnDelete::SetNull)?;
+
+        append_records(orders, order("0"))?;
+        append_records(notes, order("0"))?;
+
+        set_hooks(
+            notes,
+            Hooks::default().before_update(|_, _, _| Err(Error::custom("notes are kept"))),
+        );
+
+        assert!(matches!(
+            delete::<Customer>(customers, "0"),
+            Err(Error::Custom(_))
+        ));
+
+        remove_hooks(notes);
+
+        assert_eq!(find::<Order>(orders, "0")?, order("0"));
+        assert_eq!(find::<Order>(notes, "0")?, order("0"));
+        assert_eq!(get_table_records::<Customer>(customers)?.len(), 2);
+
+        drop_table(orders)?;
+        drop_table(notes)?;
+        drop_table(customers)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_set_references_to_null() -> Result<()> {
+        let (customers, orders) = ("ref_null_customers", "ref_null_orders");
+
+        create_customers(customers)?;
+        create_empty_table::<Order>(orders)?;
+
+        add_reference(orders, "customer", customers, OnDelete::SetNull)?;
+
+        append_records(orders, order("0"))?;
+        append_records(orders, order("1"))?;
+
+        drop_table(customers)?;
+
+        assert_eq!(find::<Order>(orders, "0")?, Order { customer: None });
+        assert_eq!(find::<Order>(orders, "1")?, Order { customer: None });
+
+        drop_table(orders)?;
+
+        Ok(())
+    }
+}
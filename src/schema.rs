@@ -0,0 +1,590 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Schema module.
+//!
+//! A table can be given a JSON Schema that every record written to it must match, kept in a
+//! hidden file next to the table file. The schema is checked on every write, including whole
+//! tables handed over with `store_json` or `update_json`, before anything is written.
+//!
+//! Only a subset of JSON Schema is understood: `type`, `enum`, `const`, `minLength`,
+//! `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`, `multipleOf`,
+//! `properties`, `required`, `additionalProperties`, `minProperties`, `maxProperties`, `items`,
+//! `minItems`, `maxItems`, `uniqueItems`, `allOf`, `anyOf`, `oneOf` and `not`, along with the
+//! annotations `$schema`, `$id`, `$comment`, `title`, `description`, `default`, `examples` and
+//! `format`, which are not checked. Schemas using anything else are refused.
+
+use std::fs;
+use std::io;
+
+use serde_json::Value;
+
+use super::errors::{Error, Result, ValidationError};
+use super::{Change, TableData, get_table, lock_writes, sidecar, table_exists};
+
+pub(crate) const SIDECAR: &str = "schema";
+
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "format",
+];
+
+/// Requires every record of `table` to match `schema` from now on.
+///
+/// Fails with `Error::Validation` if the schema uses keywords that are not understood, or if the
+/// records already stored do not match it.
+pub fn set_schema(table: &str, schema: &Value) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut errors = Vec::new();
+
+    check_schema(schema, "", &mut errors);
+
+    if !errors.is_empty() {
+        return Err(Error::Validation {
+            table: table.to_string(),
+            errors,
+        });
+    }
+
+    validate_records(table, schema, get_table::<Value>(table)?.records.iter())?;
+
+    super::write_json(&sidecar(table, SIDECAR), schema)
+}
+
+/// Returns the schema of `table`, if it has one.
+pub fn get_schema(table: &str) -> Result<Option<Value>> {
+    match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => Ok(Some(serde_json::from_reader(io::BufReader::new(file))?)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            Ok(None)
+        }
+        Err(err) => Err(Error::Io(err)),
+    }
+}
+
+/// Lets `table` hold records of any shape again.
+pub fn drop_schema(table: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    if !table_exists(table) {
+        return Err(Error::NoSuchTable(table.to_string()));
+    }
+
+    match fs::remove_file(sidecar(table, SIDECAR)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(Error::from),
+    }
+}
+
+// Checks the records a write inserts or updates against the table's schema
+pub(crate) fn check(table: &str, changes: &[Change]) -> Result<()> {
+    if !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let schema = match get_schema(table)? {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    let written = changes.iter().filter_map(|change| match change {
+        Change::Insert { id, new } | Change::Update { id, new, .. } => Some((id, new)),
+        Change::Delete { .. } | Change::Clear => None,
+    });
+
+    validate_records(table, &schema, written)
+}
+
+// Checks a whole table handed over as JSON text against the table's schema
+pub(crate) fn check_json(table: &str, json: &str) -> Result<()> {
+    if !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let schema = match get_schema(table)? {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    match serde_json::from_str::<TableData<Value>>(json) {
+        Ok(data) => validate_records(table, &schema, data.records.iter()),
+        Err(err) => Err(Error::Validation {
+            table: table.to_string(),
+            errors: vec![ValidationError {
+                path: String::new(),
+                message: format!("is not a table: {}", err),
+            }],
+        }),
+    }
+}
+
+fn validate_records<'a, I>(table: &str, schema: &Value, records: I) -> Result<()>
+where
+    I: Iterator<Item = (&'a String, &'a Value)>,
+{
+    let mut errors = Vec::new();
+
+    for (id, record) in records {
+        validate(schema, record, &pointer("", id), &mut errors);
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::Validation {
+        table: table.to_string(),
+        errors,
+    })
+}
+
+// Validator **************************************************************************************
+
+fn validate(schema: &Value, value: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Object(schema) => schema,
+        _ => return fail(path, "is not allowed here", errors),
+    };
+
+    let report = |message: String, errors: &mut Vec<ValidationError>| fail(path, &message, errors);
+
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::Array(types) => types.iter().any(|t| has_type(value, t)),
+            t => has_type(value, t),
+        };
+
+        if !matches {
+            report(format!("is not of type {}", types), errors);
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum")
+        && !allowed.contains(value)
+    {
+        report(
+            format!("is not one of {}", Value::Array(allowed.clone())),
+            errors,
+        );
+    }
+
+    if let Some(constant) = schema.get("const")
+        && constant != value
+    {
+        report(format!("is not {}", constant), errors);
+    }
+
+    match value {
+        Value::String(string) => {
+            let length = string.chars().count() as f64;
+
+            if let Some(min) = number(schema, "minLength")
+                && length < min
+            {
+                report(format!("is shorter than {} characters", min), errors);
+            }
+
+            if let Some(max) = number(schema, "maxLength")
+                && length > max
+            {
+                report(format!("is longer than {} characters", max), errors);
+            }
+        }
+        Value::Number(exact) => {
+            let n = exact.as_f64().unwrap_or(f64::NAN);
+
+            if let Some(min) = number(schema, "minimum")
+                && n < min
+            {
+                report(format!("is less than {}", min), errors);
+            }
+
+            if let Some(max) = number(schema, "maximum")
+                && n > max
+            {
+                report(format!("is greater than {}", max), errors);
+            }
+
+            if let Some(min) = number(schema, "exclusiveMinimum")
+                && n <= min
+            {
+                report(format!("is not greater than {}", min), errors);
+            }
+
+            if let Some(max) = number(schema, "exclusiveMaximum")
+                && n >= max
+            {
+                report(format!("is not less than {}", max), errors);
+            }
+
+            if let Some(factor) = schema.get("multipleOf")
+                && !is_multiple(exact, factor)
+            {
+                report(format!("is not a multiple of {}", factor), errors);
+            }
+        }
+        Value::Object(object) => {
+            let count = object.len() as f64;
+
+            if let Some(min) = number(schema, "minProperties")
+                && count < min
+            {
+                report(format!("has fewer than {} properties", min), errors);
+            }
+
+            if let Some(max) = number(schema, "maxProperties")
+                && count > max
+            {
+                report(format!("has more than {} properties", max), errors);
+            }
+
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        report(format!("is missing the property \"{}\"", name), errors);
+                    }
+                }
+            }
+
+            let properties = schema.get("properties").and_then(Value::as_object);
+
+            for (name, property) in object {
+                let property_path = pointer(path, name);
+
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(property_schema) => {
+                        validate(property_schema, property, &property_path, errors)
+                    }
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate(additional, property, &property_path, errors);
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            let count = items.len() as f64;
+
+            if let Some(min) = number(schema, "minItems")
+                && count < min
+            {
+                report(format!("has fewer than {} items", min), errors);
+            }
+
+            if let Some(max) = number(schema, "maxItems")
+                && count > max
+            {
+                report(format!("has more than {} items", max), errors);
+            }
+
+            if schema.get("uniqueItems") == Some(&Value::Bool(true))
+                && items
+                    .iter()
+                    .enumerate()
+                    .any(|(i, item)| items[..i].contains(item))
+            {
+                report("has duplicate items".to_string(), errors);
+            }
+
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &pointer(path, &i.to_string()), errors);
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for subschema in all {
+            validate(subschema, value, path, errors);
+        }
+    }
+
+    if let Some(Value::Array(any)) = schema.get("anyOf")
+        && !any.iter().any(|subschema| matches(subschema, value, path))
+    {
+        report("matches none of the schemas in anyOf".to_string(), errors);
+    }
+
+    if let Some(Value::Array(one)) = schema.get("oneOf") {
+        let matching = one
+            .iter()
+            .filter(|subschema| matches(subschema, value, path))
+            .count();
+
+        if matching != 1 {
+            report(
+                format!(
+                    "matches {} of the schemas in oneOf instead of one",
+                    matching
+                ),
+                errors,
+            );
+        }
+    }
+
+    if let Some(not) = schema.get("not")
+        && matches(not, value, path)
+    {
+        report("matches the schema in not".to_string(), errors);
+    }
+}
+
+fn matches(schema: &Value, value: &Value, path: &str) -> bool {
+    let mut errors = Vec::new();
+
+    validate(schema, value, path, &mut errors);
+
+    errors.is_empty()
+}
+
+fn has_type(value: &Value, t: &Value) -> bool {
+    match (t.as_str(), value) {
+        (Some("null"), Value::Null)
+        | (Some("boolean"), Value::Bool(_))
+        | (Some("number"), Value::Number(_))
+        | (Some("string"), Value::String(_))
+        | (Some("array"), Value::Array(_))
+        | (Some("object"), Value::Object(_)) => true,
+        (Some("integer"), Value::Number(n)) => {
+            n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+// Integers are checked exactly. Anything else is allowed a few units in the last place, as 0.3
+// divided by 0.1 is not quite 3 in floating point.
+fn is_multiple(n: &serde_json::Number, factor: &Value) -> bool {
+    if let (Some(n), Some(factor)) = (n.as_i64(), factor.as_i64())
+        && factor != 0
+    {
+        return n.checked_rem(factor).is_none_or(|rem| rem == 0);
+    }
+
+    let quotient = n.as_f64().unwrap_or(f64::NAN) / factor.as_f64().unwrap_or(f64::NAN);
+
+    (quotient - quotient.round()).abs() <= 4.0 * f64::EPSILON * quotient.abs().max(1.0)
+}
+
+fn number(schema: &serde_json::Map<String, Value>, keyword: &str) -> Option<f64> {
+    schema.get(keyword).and_then(Value::as_f64)
+}
+
+fn fail(path: &str, message: &str, errors: &mut Vec<ValidationError>) {
+    errors.push(ValidationError {
+        path: path.to_string(),
+        message: message.to_string(),
+    });
+}
+
+fn pointer(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+// Refuses schemas that lean on keywords this validator would silently skip
+fn check_schema(schema: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+    let schema = match schema {
+        Value::Bool(_) => return,
+        Value::Object(schema) => schema,
+        _ => return fail(path, "is not a schema", errors),
+    };
+
+    for (keyword, value) in schema {
+        let keyword_path = pointer(path, keyword);
+
+        match keyword.as_str() {
+            "type" | "enum" | "const" | "required" | "minLength" | "maxLength" | "minimum"
+            | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" | "multipleOf"
+            | "minProperties" | "maxProperties" | "minItems" | "maxItems" | "uniqueItems" => {}
+            "additionalProperties" | "items" | "not" => check_schema(value, &keyword_path, errors),
+            "properties" => match value.as_object() {
+                Some(properties) => {
+                    for (name, property) in properties {
+                        check_schema(property, &pointer(&keyword_path, name), errors);
+                    }
+                }
+                None => fail(&keyword_path, "is not an object", errors),
+            },
+            "allOf" | "anyOf" | "oneOf" => match value.as_array() {
+                Some(subschemas) => {
+                    for (i, subschema) in subschemas.iter().enumerate() {
+                        check_schema(subschema, &pointer(&keyword_path, &i.to_string()), errors);
+                    }
+                }
+                None => fail(&keyword_path, "is not an array", errors),
+            },
+            _ if ANNOTATIONS.contains(&keyword.as_str()) => {}
+            _ => fail(&keyword_path, "is not a supported keyword", errors),
+        }
+    }
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_schema {
+    use super::*;
+    use crate::{
+        append_records, batch_insert, create_empty_table, drop_table, update_json, update_record,
+    };
+    use serde_json::json;
+
+    fn user_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"enum": ["admin", "staff"]}},
+            },
+            "additionalProperties": false,
+        })
+    }
+
+    fn paths(result: Result<()>) -> Vec<String> {
+        match result {
+            Err(Error::Validation { errors, .. }) => errors.into_iter().map(|e| e.path).collect(),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_validate_every_write() -> Result<()> {
+        let table = "schema_write_test";
+
+        create_empty_table::<Value>(table)?;
+
+        set_schema(table, &user_schema())?;
+
+        append_records(table, json!({"name": "Ada", "age": 36, "tags": ["admin"]}))?;
+
+        assert_eq!(
+            paths(append_records(
+                table,
+                json!({"name": "", "age": -1.5, "tags": ["root"], "x": 1})
+            )),
+            vec!["/1/age", "/1/age", "/1/name", "/1/tags/0", "/1/x"]
+        );
+
+        assert_eq!(
+            paths(batch_insert(
+                table,
+                vec![json!({"name": "Bob", "age": 1}), json!({})]
+            )),
+            vec!["/2", "/2"]
+        );
+
+        assert_eq!(
+            paths(update_record(table, "0", json!({"name": "Ada"}))),
+            vec!["/0"]
+        );
+
+        update_record(table, "0", json!({"name": "Ada", "age": 37}))?;
+
+        let table_json = r#"{"table":"t","next_id":"1","records":{"0":{"name":7,"age":1}}}"#;
+
+        assert_eq!(paths(update_json(table, table_json)), vec!["/0/name"]);
+        assert_eq!(paths(update_json(table, "not a table")), vec![""]);
+
+        assert_eq!(get_schema(table)?, Some(user_schema()));
+
+        drop_schema(table)?;
+
+        append_records(table, json!("anything"))?;
+
+        assert_eq!(get_schema(table)?, None);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_bad_schemas_and_records() -> Result<()> {
+        let table = "schema_refuse_test";
+
+        create_empty_table::<Value>(table)?;
+
+        append_records(table, json!({"name": "Ada"}))?;
+
+        let unsupported = json!({"properties": {"name": {"pattern": "^A"}}, "anyOf": [7]});
+
+        assert_eq!(
+            paths(set_schema(table, &unsupported)),
+            vec!["/anyOf/0", "/properties/name/pattern"]
+        );
+
+        assert_eq!(paths(set_schema(table, &user_schema())), vec!["/0"]);
+
+        assert_eq!(get_schema(table)?, None);
+
+        let schema = json!({
+            "oneOf": [{"required": ["name"]}, {"required": ["id"]}],
+            "not": {"required": ["password"]},
+        });
+
+        set_schema(table, &schema)?;
+
+        assert_eq!(
+            paths(append_records(table, json!({"name": "Bob", "id": 1}))),
+            vec!["/1"]
+        );
+
+        assert_eq!(
+            paths(append_records(table, json!({"id": 1, "password": "x"}))),
+            vec!["/1"]
+        );
+
+        set_schema(
+            table,
+            &json!({"properties": {"price": {"multipleOf": 0.1}}}),
+        )?;
+
+        append_records(table, json!({"price": 0.3}))?;
+
+        assert_eq!(
+            paths(append_records(table, json!({"price": 0.35}))),
+            vec!["/2/price"]
+        );
+
+        set_schema(table, &json!({"properties": {"count": {"multipleOf": 3}}}))?;
+
+        append_records(table, json!({"count": 9_007_199_254_740_993_i64}))?;
+
+        assert_eq!(
+            paths(append_records(
+                table,
+                json!({"count": 9_007_199_254_740_992_i64})
+            )),
+            vec!["/3/count"]
+        );
+
+        drop_table(table)?;
+
+        assert!(matches!(get_schema(table), Err(Error::NoSuchTable(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Full-text search module.
+//!
+//! A table can opt in to an inverted index over some of its text fields. Text is split on
+//! anything that isn't a letter or digit, lowercased and stemmed, and each term remembers where
+//! it appears so phrases can be matched. The index lives in a hidden file next to the table file,
+//! and every write through this crate keeps it up to date.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, compare_ids, expiry, get_table, json_pointer, lock_writes, sidecar, table_exists,
+};
+
+pub(crate) const SIDECAR: &str = "search";
+
+// BM25 tuning, at the usual values
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// Keeps phrases from matching across the end of one field and the start of the next
+const FIELD_GAP: usize = 1;
+
+#[derive(Serialize, Deserialize, Default)]
+struct SearchIndex {
+    fields: Vec<String>,
+    lengths: BTreeMap<String, usize>,
+    postings: BTreeMap<String, BTreeMap<String, Vec<usize>>>,
+}
+
+impl SearchIndex {
+    fn add(&mut self, id: &str, record: &Value) {
+        let mut position = 0;
+
+        for field in &self.fields {
+            for text in texts(record.pointer(field)) {
+                for term in tokenize(text) {
+                    self.postings
+                        .entry(term)
+                        .or_default()
+                        .entry(id.to_string())
+                        .or_default()
+                        .push(position);
+
+                    position += 1;
+                }
+            }
+
+            position += FIELD_GAP;
+        }
+
+        self.lengths.insert(id.to_string(), position);
+    }
+
+    fn remove(&mut self, id: &str, record: &Value) {
+        for field in &self.fields {
+            for text in texts(record.pointer(field)) {
+                for term in tokenize(text) {
+                    if let Some(documents) = self.postings.get_mut(&term) {
+                        documents.remove(id);
+
+                        if documents.is_empty() {
+                            self.postings.remove(&term);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.lengths.remove(id);
+    }
+
+    fn clear(&mut self) {
+        self.lengths.clear();
+        self.postings.clear();
+    }
+
+    // The documents matching one clause of a query, and how often each matches it
+    fn matches(&self, clause: &Clause) -> BTreeMap<String, usize> {
+        let mut matches = BTreeMap::new();
+
+        match *clause {
+            Clause::Term(ref term) => {
+                if let Some(documents) = self.postings.get(term) {
+                    for (id, positions) in documents {
+                        matches.insert(id.clone(), positions.len());
+                    }
+                }
+            }
+            Clause::Prefix(ref prefix) => {
+                let terms = self
+                    .postings
+                    .range(prefix.clone()..)
+                    .take_while(|(term, _)| term.starts_with(prefix.as_str()));
+
+                for (_, documents) in terms {
+                    for (id, positions) in documents {
+                        *matches.entry(id.clone()).or_insert(0) += positions.len();
+                    }
+                }
+            }
+            Clause::Phrase(ref terms) => {
+                let first = match terms.first().and_then(|term| self.postings.get(term)) {
+                    Some(documents) => documents,
+                    None => return matches,
+                };
+
+                for (id, positions) in first {
+                    let count = positions
+                        .iter()
+                        .filter(|&&start| {
+                            terms.iter().enumerate().skip(1).all(|(offset, term)| {
+                                self.postings
+                                    .get(term)
+                                    .and_then(|documents| documents.get(id))
+                                    .is_some_and(|found| found.contains(&(start + offset)))
+                            })
+                        })
+                        .count();
+
+                    if count > 0 {
+                        matches.insert(id.clone(), count);
+                    }
+                }
+            }
+        }
+
+        matches
+    }
+}
+
+enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+/// Opts `table` in to full-text search over the text held at each of `fields`, and indexes the
+/// records already stored.
+///
+/// Each field is either a top-level field name or a JSON pointer, and may hold a string or an
+/// array of strings. Creating the index again replaces it.
+pub fn create_search_index(table: &str, fields: &[&str]) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    if !table_exists(table) {
+        return Err(Error::NoSuchTable(table.to_string()));
+    }
+
+    let mut index = SearchIndex {
+        fields: fields.iter().map(|field| json_pointer(field)).collect(),
+        ..SearchIndex::default()
+    };
+
+    for (id, record) in &get_table::<Value>(table)?.records {
+        index.add(id, record);
+    }
+
+    write_index(table, &index)
+}
+
+/// Removes the full-text index of `table`.
+pub fn drop_search_index(table: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    match fs::remove_file(sidecar(table, SIDECAR)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            Err(Error::NoSuchIndex(SIDECAR.to_string()))
+        }
+        result => result.map_err(Error::from),
+    }
+}
+
+/// Returns the ids of the records of `table` matching `query`, most relevant first, along with
+/// their scores.
+///
+/// A query is a list of words, all of which must appear. Words are matched after the same
+/// lowercasing and stemming as the indexed text, a word ending in `*` matches any word starting
+/// with it, and words in double quotes must appear next to each other in that order.
+pub fn search(table: &str, query: &str) -> Result<Vec<(String, f64)>> {
+    let index = read_index(table)?;
+
+    let clauses = parse(query);
+
+    if clauses.is_empty() || index.lengths.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let documents = index.lengths.len() as f64;
+
+    let average = index.lengths.values().sum::<usize>() as f64 / documents;
+
+    let mut scores: Option<BTreeMap<String, f64>> = None;
+
+    for clause in &clauses {
+        let matches = index.matches(clause);
+
+        let idf =
+            (1.0 + (documents - matches.len() as f64 + 0.5) / (matches.len() as f64 + 0.5)).ln();
+
+        let clause_scores = matches.into_iter().map(|(id, frequency)| {
+            let length = index.lengths.get(&id).copied().unwrap_or_default() as f64;
+
+            let frequency = frequency as f64;
+
+            let score = idf * frequency * (K1 + 1.0)
+                / (frequency + K1 * (1.0 - B + B * length / average.max(1.0)));
+
+            (id, score)
+        });
+
+        scores = Some(match scores {
+            None => clause_scores.collect(),
+            Some(mut scores) => {
+                let clause_scores: BTreeMap<String, f64> = clause_scores.collect();
+
+                scores.retain(|id, _| clause_scores.contains_key(id));
+
+                for (id, score) in scores.iter_mut() {
+                    *score += clause_scores[id];
+                }
+
+                scores
+            }
+        });
+    }
+
+    let expired = expiry::expired_ids(table)?;
+
+    let mut hits: Vec<(String, f64)> = scores
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(id, _)| !expired.contains(id))
+        .collect();
+
+    hits.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| compare_ids(&a.0, &b.0))
+    });
+
+    Ok(hits)
+}
+
+// Brings the full-text index of a table in line with a write that has just been committed
+pub(crate) fn apply(table: &str, changes: &[Change]) -> Result<()> {
+    if changes.is_empty() || !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let mut index = read_index(table)?;
+
+    for change in changes {
+        match *change {
+            Change::Insert { ref id, ref new } => index.add(id, new),
+            Change::Update {
+                ref id,
+                ref old,
+                ref new,
+            } => {
+                index.remove(id, old);
+                index.add(id, new);
+            }
+            Change::Delete { ref id, ref old } => index.remove(id, old),
+            Change::Clear => index.clear(),
+        }
+    }
+
+    write_index(table, &index)
+}
+
+fn texts(value: Option<&Value>) -> Vec<&str> {
+    match value {
+        Some(Value::String(text)) => vec![text],
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| stem(&word.to_lowercase()))
+}
+
+// A light suffix stripper, enough for "tickets", "ticketing" and "ticketed" to meet at "ticket"
+fn stem(word: &str) -> String {
+    let count = word.chars().count();
+
+    let rules: [(&str, &str, usize); 10] = [
+        ("sses", "ss", 5),
+        ("shes", "sh", 5),
+        ("ches", "ch", 5),
+        ("xes", "x", 4),
+        ("ies", "y", 5),
+        ("ing", "", 6),
+        ("edly", "", 7),
+        ("ed", "", 5),
+        ("ly", "", 5),
+        ("s", "", 4),
+    ];
+
+    for (suffix, replacement, min) in rules {
+        if count >= min && word.ends_with(suffix) {
+            if suffix == "s" && (word.ends_with("ss") || word.ends_with("us")) {
+                break;
+            }
+
+            return format!("{}{}", &word[..word.len() - suffix.len()], replacement);
+        }
+    }
+
+    word.to_string()
+}
+
+fn parse(query: &str) -> Vec<Clause> {
+    let mut clauses = Vec::new();
+
+    for (n, part) in query.split('"').enumerate() {
+        // Every odd part sits between a pair of quotes
+        if n % 2 == 1 {
+            let terms: Vec<String> = tokenize(part).collect();
+
+            match terms.len() {
+                0 => {}
+                1 => clauses.extend(terms.into_iter().map(Clause::Term)),
+                _ => clauses.push(Clause::Phrase(terms)),
+            }
+
+            continue;
+        }
+
+        for word in part.split_whitespace() {
+            match word.strip_suffix('*') {
+                Some(prefix) => {
+                    let prefix: String = prefix
+                        .chars()
+                        .filter(|c| c.is_alphanumeric())
+                        .collect::<String>()
+                        .to_lowercase();
+
+                    if !prefix.is_empty() {
+                        clauses.push(Clause::Prefix(prefix));
+                    }
+                }
+                None => clauses.extend(tokenize(word).map(Clause::Term)),
+            }
+        }
+    }
+
+    clauses
+}
+
+fn read_index(table: &str) -> Result<SearchIndex> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Err(Error::NoSuchIndex(SIDECAR.to_string()));
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_index(table: &str, index: &SearchIndex) -> Result<()> {
+    super::write_json(&sidecar(table, SIDECAR), index)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_search {
+    use super::*;
+    use crate::{
+        append_records, batch_insert, create_empty_table, delete, drop_table, update_record,
+    };
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Ticket {
+        pub title: String,
+        pub body: String,
+    }
+
+    fn ticket(title: &str, body: &str) -> Ticket {
+        Ticket {
+            title: title.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    fn ids(hits: Vec<(String, f64)>) -> Vec<String> {
+        hits.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn create_tickets(table: &str) -> Result<()> {
+        create_empty_table::<Ticket>(table)?;
+
+        batch_insert(
+            table,
+            vec![
+                ticket("Login broken", "The login page crashes on submit."),
+                ticket("Billing", "Invoices are sent twice. Billing crashed twice."),
+                ticket("Crash report", "App crashing when printing invoices"),
+            ],
+        )?;
+
+        create_search_index(table, &["title", "/body"])
+    }
+
+    #[test]
+    fn can_rank_stemmed_terms() -> Result<()> {
+        let table = "search_rank_test";
+
+        create_tickets(table)?;
+
+        assert_eq!(ids(search(table, "CRASH")?), vec!["2", "0", "1"]);
+
+        assert_eq!(ids(search(table, "invoice crash")?), vec!["2", "1"]);
+
+        assert!(search(table, "refund")?.is_empty());
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_match_phrases_and_prefixes() -> Result<()> {
+        let table = "search_phrase_test";
+
+        create_tickets(table)?;
+
+        assert_eq!(ids(search(table, "\"sent twice\"")?), vec!["1"]);
+
+        assert!(search(table, "\"twice sent\"")?.is_empty());
+
+        assert!(search(table, "\"broken the\"")?.is_empty());
+
+        assert_eq!(ids(search(table, "bill*")?), vec!["1"]);
+
+        assert_eq!(ids(search(table, "log* crash")?), vec!["0"]);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_keep_the_search_index_up_to_date() -> Result<()> {
+        let table = "search_write_test";
+
+        create_tickets(table)?;
+
+        append_records(table, ticket("Refund", "Customer wants a refund"))?;
+
+        assert_eq!(ids(search(table, "refunds")?), vec!["3"]);
+
+        update_record(table, "3", ticket("Refund", "Sorted"))?;
+
+        assert!(search(table, "customer")?.is_empty());
+
+        delete::<Ticket>(table, "3")?;
+
+        assert!(search(table, "refund")?.is_empty());
+
+        drop_search_index(table)?;
+
+        assert!(matches!(search(table, "crash"), Err(Error::NoSuchIndex(_))));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,682 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Geospatial index module.
+//!
+//! A spatial index reads a point out of two numeric fields of every record and files it under a
+//! square cell of a fixed grid, so box and radius queries only look at the cells they overlap.
+//! The indexes of a table live together in a hidden file next to the table file, and every write
+//! through this crate keeps them up to date.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, expiry, find_many, get_table, json_pointer, lock_writes, sidecar, table_exists,
+};
+
+pub(crate) const SIDECAR: &str = "spatial";
+
+// Mean radius of the earth, in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+// Meters per degree of latitude, close enough for sizing search boxes
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Where a spatial index reads the points of a table from, and how it measures between them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Space {
+    /// Points are `(x, y)` on a flat plane, and distances are euclidean in the same units.
+    Planar { x: String, y: String },
+
+    /// Points are `(lat, lon)` in degrees, and distances are great-circle distances in meters.
+    Geographic { lat: String, lon: String },
+}
+
+impl Space {
+    /// A flat plane reading `x` and `y` from the given field names or JSON pointers.
+    pub fn planar(x: &str, y: &str) -> Space {
+        Space::Planar {
+            x: json_pointer(x),
+            y: json_pointer(y),
+        }
+    }
+
+    /// The surface of the earth, reading latitude and longitude from the given field names or
+    /// JSON pointers.
+    pub fn geographic(lat: &str, lon: &str) -> Space {
+        Space::Geographic {
+            lat: json_pointer(lat),
+            lon: json_pointer(lon),
+        }
+    }
+
+    fn point(&self, record: &Value) -> Option<(f64, f64)> {
+        let (a, b) = match *self {
+            Space::Planar { ref x, ref y } => (x, y),
+            Space::Geographic { ref lat, ref lon } => (lat, lon),
+        };
+
+        Some((record.pointer(a)?.as_f64()?, record.pointer(b)?.as_f64()?))
+    }
+
+    fn distance(&self, from: (f64, f64), to: (f64, f64)) -> f64 {
+        match *self {
+            Space::Planar { .. } => ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt(),
+            Space::Geographic { .. } => {
+                let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+                let dlat = lat2 - lat1;
+                let dlon = (to.1 - from.1).to_radians();
+
+                let h = (dlat / 2.0).sin().powi(2)
+                    + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+
+                2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SpatialIndex {
+    space: Space,
+    cell: f64,
+    points: BTreeMap<String, (f64, f64)>,
+    cells: BTreeMap<String, Vec<String>>,
+}
+
+impl SpatialIndex {
+    fn add(&mut self, id: &str, record: &Value) {
+        if let Some(point) = self.space.point(record) {
+            let key = cell_key(self.cell_of(point));
+
+            self.cells.entry(key).or_default().push(id.to_string());
+            self.points.insert(id.to_string(), point);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(point) = self.points.remove(id) {
+            let key = cell_key(self.cell_of(point));
+
+            if let Some(ids) = self.cells.get_mut(&key) {
+                ids.retain(|indexed| indexed != id);
+
+                if ids.is_empty() {
+                    self.cells.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.points.clear();
+        self.cells.clear();
+    }
+
+    fn cell_of(&self, point: (f64, f64)) -> (i64, i64) {
+        (
+            (point.0 / self.cell).floor() as i64,
+            (point.1 / self.cell).floor() as i64,
+        )
+    }
+
+    // The ids filed in the cells overlapping a box, falling back to every point when the box
+    // covers more cells than there are points. The cells are counted in floating point, as a box
+    // far enough out has more of them than an i64 can count.
+    fn candidates(&self, min: (f64, f64), max: (f64, f64)) -> Vec<(&String, (f64, f64))> {
+        let span =
+            |low: f64, high: f64| (high / self.cell).floor() - (low / self.cell).floor() + 1.0;
+
+        let cells = span(min.0, max.0) * span(min.1, max.1);
+
+        if cells.is_nan() || cells > self.cells.len() as f64 {
+            return self.points.iter().map(|(id, point)| (id, *point)).collect();
+        }
+
+        let (low, high) = (self.cell_of(min), self.cell_of(max));
+
+        let mut found = Vec::new();
+
+        for i in low.0..=high.0 {
+            for j in low.1..=high.1 {
+                if let Some(ids) = self.cells.get(&cell_key((i, j))) {
+                    found.extend(ids.iter().filter_map(|id| {
+                        self.points
+                            .get_key_value(id)
+                            .map(|(id, point)| (id, *point))
+                    }));
+                }
+            }
+        }
+
+        found
+    }
+
+    // Boxes sure to hold every point within `radius` of `center`. A geographic box running over
+    // the antimeridian is split in two, one either side of it.
+    fn bounds(&self, center: (f64, f64), radius: f64) -> Vec<((f64, f64), (f64, f64))> {
+        match self.space {
+            Space::Planar { .. } => vec![(
+                (center.0 - radius, center.1 - radius),
+                (center.0 + radius, center.1 + radius),
+            )],
+            Space::Geographic { .. } => {
+                let dlat = radius / METERS_PER_DEGREE;
+
+                let cos = (center.0.abs() + dlat).min(90.0).to_radians().cos();
+
+                let dlon = match cos > 1e-6 {
+                    true => (dlat / cos).min(180.0),
+                    false => 180.0,
+                };
+
+                let (south, north) = (center.0 - dlat, center.0 + dlat);
+                let (west, east) = (center.1 - dlon, center.1 + dlon);
+
+                if dlon >= 180.0 {
+                    vec![((south, -180.0), (north, 180.0))]
+                } else if west < -180.0 {
+                    vec![
+                        ((south, west + 360.0), (north, 180.0)),
+                        ((south, -180.0), (north, east)),
+                    ]
+                } else if east > 180.0 {
+                    vec![
+                        ((south, west), (north, 180.0)),
+                        ((south, -180.0), (north, east - 360.0)),
+                    ]
+                } else {
+                    vec![((south, west), (north, east))]
+                }
+            }
+        }
+    }
+
+    // The live points within `radius` of `center`, each once, even when both halves of a box
+    // split over the antimeridian fell back to every point
+    fn within_radius(
+        &self,
+        center: (f64, f64),
+        radius: f64,
+        expired: &BTreeSet<String>,
+    ) -> Vec<(String, f64)> {
+        let mut found: Vec<(String, f64)> = self
+            .bounds(center, radius)
+            .into_iter()
+            .flat_map(|(min, max)| self.candidates(min, max))
+            .filter(|(id, _)| !expired.contains(*id))
+            .map(|(id, point)| (id.clone(), self.space.distance(center, point)))
+            .filter(|&(_, distance)| distance <= radius)
+            .collect();
+
+        sort_by_distance(&mut found);
+
+        found.dedup_by(|a, b| a.0 == b.0);
+
+        found
+    }
+
+    fn nearest(
+        &self,
+        center: (f64, f64),
+        k: usize,
+        expired: &BTreeSet<String>,
+    ) -> Vec<(String, f64)> {
+        let mut found: Vec<(String, f64)> = Vec::new();
+
+        let live = self
+            .points
+            .keys()
+            .filter(|id| !expired.contains(*id))
+            .count();
+
+        if k == 0 || live == 0 {
+            return found;
+        }
+
+        // Double the radius until the circle holds k points. Anything outside it is further away
+        // than everything inside, so those are the k nearest. It starts a cell wide, which for
+        // geographic points means turning degrees into the meters distances are measured in.
+        let mut radius = match self.space {
+            Space::Planar { .. } => self.cell,
+            Space::Geographic { .. } => self.cell * METERS_PER_DEGREE,
+        };
+
+        loop {
+            found = self.within_radius(center, radius, expired);
+
+            if found.len() >= k || found.len() == live {
+                break;
+            }
+
+            radius *= 2.0;
+
+            if !radius.is_finite() {
+                break;
+            }
+        }
+
+        found.truncate(k);
+
+        found
+    }
+}
+
+type SpatialIndexes = BTreeMap<String, SpatialIndex>;
+
+/// Creates the spatial index `name` over the points `space` reads from each record of `table`,
+/// filed under square cells `cell` wide, and builds it from the records already stored.
+///
+/// `cell` is in the units of the points, so degrees for `Space::Geographic`. A good cell is about
+/// the size of a typical query. Records missing either coordinate are left out. Fails with
+/// `Error::InvalidInput` unless `cell` is finite and above zero.
+pub fn create_spatial_index(table: &str, name: &str, space: Space, cell: f64) -> Result<()> {
+    if !(cell.is_finite() && cell > 0.0) {
+        return Err(Error::InvalidInput(format!(
+            "a spatial index needs a finite, positive cell size, not {}",
+            cell
+        )));
+    }
+
+    let _lock = lock_writes()?;
+
+    let mut indexes = read_indexes(table)?;
+
+    let mut index = SpatialIndex {
+        space,
+        cell,
+        points: BTreeMap::new(),
+        cells: BTreeMap::new(),
+    };
+
+    for (id, record) in &get_table::<Value>(table)?.records {
+        index.add(id, record);
+    }
+
+    indexes.insert(name.to_string(), index);
+
+    write_indexes(table, &indexes)
+}
+
+/// Removes the spatial index `name` of `table`.
+pub fn drop_spatial_index(table: &str, name: &str) -> Result<()> {
+    let _lock = lock_writes()?;
+
+    let mut indexes = read_indexes(table)?;
+
+    if indexes.remove(name).is_none() {
+        return Err(Error::NoSuchIndex(name.to_string()));
+    }
+
+    if indexes.is_empty() {
+        fs::remove_file(sidecar(table, SIDECAR))?;
+
+        return Ok(());
+    }
+
+    write_indexes(table, &indexes)
+}
+
+/// Returns the records of `table` whose points lie within the box from `min` to `max`, in id
+/// order.
+///
+/// Fails with `Error::InvalidInput` if a coordinate is not finite, as do the other spatial
+/// queries.
+pub fn within_bounds<T>(
+    table: &str,
+    name: &str,
+    min: (f64, f64),
+    max: (f64, f64),
+) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    check_finite(&[min.0, min.1, max.0, max.1])?;
+
+    let index = read_index(table, name)?;
+
+    let mut ids: Vec<String> = index
+        .candidates(min, max)
+        .into_iter()
+        .filter(|(_, p)| p.0 >= min.0 && p.0 <= max.0 && p.1 >= min.1 && p.1 <= max.1)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    ids.sort_by(|a, b| super::compare_ids(a, b));
+
+    find_many(table, &ids)
+}
+
+/// Returns the records of `table` whose points lie within `radius` of `center`, nearest first.
+///
+/// `radius` is in meters for `Space::Geographic` and in the units of the points otherwise.
+pub fn within_radius<T>(
+    table: &str,
+    name: &str,
+    center: (f64, f64),
+    radius: f64,
+) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    check_finite(&[center.0, center.1, radius])?;
+
+    let index = read_index(table, name)?;
+
+    let ids: Vec<String> = index
+        .within_radius(center, radius, &expiry::expired_ids(table)?)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+
+    find_many(table, &ids)
+}
+
+/// Returns the `k` records of `table` whose points lie nearest to `center`, nearest first.
+pub fn nearest<T>(table: &str, name: &str, center: (f64, f64), k: usize) -> Result<Vec<(String, T)>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    check_finite(&[center.0, center.1])?;
+
+    let index = read_index(table, name)?;
+
+    let ids: Vec<String> = index
+        .nearest(center, k, &expiry::expired_ids(table)?)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+
+    find_many(table, &ids)
+}
+
+// Brings the spatial indexes of a table in line with a write that has just been committed
+pub(crate) fn apply(table: &str, changes: &[Change]) -> Result<()> {
+    if changes.is_empty() || !sidecar(table, SIDECAR).exists() {
+        return Ok(());
+    }
+
+    let mut indexes = read_indexes(table)?;
+
+    for index in indexes.values_mut() {
+        for change in changes {
+            match *change {
+                Change::Insert { ref id, ref new } => index.add(id, new),
+                Change::Update {
+                    ref id, ref new, ..
+                } => {
+                    index.remove(id);
+                    index.add(id, new);
+                }
+                Change::Delete { ref id, .. } => index.remove(id),
+                Change::Clear => index.clear(),
+            }
+        }
+    }
+
+    write_indexes(table, &indexes)
+}
+
+fn check_finite(values: &[f64]) -> Result<()> {
+    if values.iter().all(|value| value.is_finite()) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidInput(format!(
+        "spatial queries need finite numbers, not {:?}",
+        values
+    )))
+}
+
+fn cell_key(cell: (i64, i64)) -> String {
+    format!("{},{}", cell.0, cell.1)
+}
+
+// Nearest first, with ties in id order
+fn sort_by_distance(found: &mut [(String, f64)]) {
+    found.sort_by(|a, b| {
+        a.1.partial_cmp(&b.1)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| super::compare_ids(&a.0, &b.0))
+    });
+}
+
+fn read_index(table: &str, name: &str) -> Result<SpatialIndex> {
+    match read_indexes(table)?.remove(name) {
+        Some(index) => Ok(index),
+        None => Err(Error::NoSuchIndex(name.to_string())),
+    }
+}
+
+fn read_indexes(table: &str) -> Result<SpatialIndexes> {
+    let file = match fs::File::open(sidecar(table, SIDECAR)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if !table_exists(table) {
+                return Err(Error::NoSuchTable(table.to_string()));
+            }
+
+            return Ok(SpatialIndexes::new());
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    serde_json::from_reader(io::BufReader::new(file)).map_err(Error::from)
+}
+
+fn write_indexes(table: &str, indexes: &SpatialIndexes) -> Result<()> {
+    super::write_json(&sidecar(table, SIDECAR), indexes)
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_spatial_index {
+    use super::*;
+    use crate::{
+        append_records, append_records_with_ttl, batch_insert, create_empty_table, delete,
+        drop_table,
+    };
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Coordinates {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct City {
+        pub lat: f64,
+        pub lon: f64,
+    }
+
+    fn ids<T>(records: Vec<(String, T)>) -> Vec<String> {
+        records.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn create_grid(table: &str) -> Result<()> {
+        create_empty_table::<Coordinates>(table)?;
+
+        let points = (0..100)
+            .map(|n| Coordinates {
+                x: n % 10 * 10,
+                y: n / 10 * 10,
+            })
+            .collect();
+
+        batch_insert(table, points)?;
+
+        create_spatial_index(table, "position", Space::planar("x", "y"), 25.0)
+    }
+
+    #[test]
+    fn can_query_planar_points() -> Result<()> {
+        let table = "spatial_planar_test";
+
+        create_grid(table)?;
+
+        let found = within_bounds::<Coordinates>(table, "position", (15.0, 15.0), (30.0, 20.0))?;
+
+        assert_eq!(ids(found), vec!["22", "23"]);
+
+        let found = within_radius::<Coordinates>(table, "position", (40.0, 40.0), 10.0)?;
+
+        assert_eq!(ids(found), vec!["44", "34", "43", "45", "54"]);
+
+        let found = nearest::<Coordinates>(table, "position", (-100.0, -100.0), 3)?;
+
+        assert_eq!(ids(found), vec!["0", "1", "10"]);
+
+        let found =
+            within_bounds::<Coordinates>(table, "position", (-1e300, -1e300), (1e300, 1e300))?;
+
+        assert_eq!(found.len(), 100);
+
+        assert!(matches!(
+            within_radius::<Coordinates>(table, "position", (0.0, 0.0), f64::INFINITY),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            nearest::<Coordinates>(table, "position", (f64::NAN, 0.0), 1),
+            Err(Error::InvalidInput(_))
+        ));
+
+        for cell in [0.0, -1.0, f64::NAN] {
+            assert!(matches!(
+                create_spatial_index(table, "other", Space::planar("x", "y"), cell),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_query_geographic_points() -> Result<()> {
+        let table = "spatial_geographic_test";
+
+        create_empty_table::<City>(table)?;
+
+        batch_insert(
+            table,
+            vec![
+                City {
+                    lat: 48.8566,
+                    lon: 2.3522,
+                },
+                City {
+                    lat: 51.5074,
+                    lon: -0.1278,
+                },
+                City {
+                    lat: 52.5200,
+                    lon: 13.4050,
+                },
+                City {
+                    lat: 40.7128,
+                    lon: -74.0060,
+                },
+            ],
+        )?;
+
+        create_spatial_index(table, "location", Space::geographic("lat", "lon"), 1.0)?;
+
+        // Paris to London is about 344km, and Paris to Berlin about 878km
+        let found = within_radius::<City>(table, "location", (48.8566, 2.3522), 400_000.0)?;
+
+        assert_eq!(ids(found), vec!["0", "1"]);
+
+        let found = nearest::<City>(table, "location", (50.0, 5.0), 3)?;
+
+        assert_eq!(ids(found), vec!["0", "1", "2"]);
+
+        // Either side of the antimeridian, about 2km apart
+        batch_insert(
+            table,
+            vec![
+                City {
+                    lat: 0.0,
+                    lon: 179.99,
+                },
+                City {
+                    lat: 0.0,
+                    lon: -179.99,
+                },
+            ],
+        )?;
+
+        let found = within_radius::<City>(table, "location", (0.0, 179.99), 10_000.0)?;
+
+        assert_eq!(ids(found), vec!["4", "5"]);
+
+        let found = nearest::<City>(table, "location", (0.0, -179.99), 2)?;
+
+        assert_eq!(ids(found), vec!["5", "4"]);
+
+        // Past the antimeridian, and gone at once
+        append_records_with_ttl(
+            table,
+            City {
+                lat: 0.0,
+                lon: -179.98,
+            },
+            Duration::ZERO,
+        )?;
+
+        let found = nearest::<City>(table, "location", (0.0, 179.99), 3)?;
+
+        assert_eq!(found.len(), 3);
+        assert_eq!(ids(found)[..2], ["4", "5"]);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_keep_spatial_indexes_up_to_date() -> Result<()> {
+        let table = "spatial_write_test";
+
+        create_grid(table)?;
+
+        append_records(table, Coordinates { x: 1000, y: 1000 })?;
+
+        let found = nearest::<Coordinates>(table, "position", (990.0, 990.0), 1)?;
+
+        assert_eq!(ids(found), vec!["100"]);
+
+        delete::<Coordinates>(table, "100")?;
+
+        let found = nearest::<Coordinates>(table, "position", (990.0, 990.0), 1)?;
+
+        assert_eq!(ids(found), vec!["99"]);
+
+        drop_spatial_index(table, "position")?;
+
+        assert!(matches!(
+            nearest::<Coordinates>(table, "position", (0.0, 0.0), 1),
+            Err(Error::NoSuchIndex(_))
+        ));
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,589 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Streaming module.
+//!
+//! Reads a table file a buffer at a time, lifting one record at a time out of it, so memory use
+//! stays bounded by the largest record rather than the whole table. Expired records are skipped,
+//! which takes a first pass over the file to read their expiry times.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::marker::PhantomData;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::Error as DeError;
+
+use super::errors::{Error, Result};
+use super::{Records, db_table, expiry, migration, read_table};
+
+/// An iterator over the records of a table, read straight from disk in id order.
+///
+/// Built by `iter_records`. Iteration stops after the first error.
+pub struct RecordIter<T> {
+    scanner: Scanner<BufReader<File>>,
+    expired: BTreeSet<String>,
+    buffer: Vec<u8>,
+    state: State,
+    marker: PhantomData<T>,
+}
+
+#[derive(PartialEq)]
+enum State {
+    First,
+    Next,
+    Done,
+}
+
+/// Streams the records of `table` one at a time, without ever holding the whole table in
+/// memory.
+pub fn iter_records<T>(table: &str) -> Result<RecordIter<T>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    open_records(table)
+}
+
+fn open_records<T>(table: &str) -> Result<RecordIter<T>> {
+    if migration::latest_version(table) > 0 {
+        // Brings the table up to date before it is streamed
+        read_table(table)?;
+    }
+
+    let expired = expiry::expired_ids(table)?;
+
+    let mut scanner = open_table(table)?;
+
+    let found = scanner.seek_object("records")?;
+
+    Ok(RecordIter {
+        scanner,
+        expired,
+        buffer: Vec::new(),
+        state: if found { State::First } else { State::Done },
+        marker: PhantomData,
+    })
+}
+
+/// Like `find_by`, but streams the table so only the matching records are held in memory.
+pub fn stream_find_by<T, F>(table: &str, predicate: F) -> Result<Records<T>>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+    F: Fn(&T) -> bool,
+{
+    let mut matching_records = Records::new();
+
+    for entry in iter_records::<T>(table)? {
+        let (id, record) = entry?;
+
+        if predicate(&record) {
+            matching_records.insert(id, record);
+        }
+    }
+
+    Ok(matching_records)
+}
+
+/// Like `count_records`, but streams the table, skipping over every record without keeping it.
+pub fn stream_count_records(table: &str) -> Result<usize> {
+    let mut records = open_records::<()>(table)?;
+
+    let mut count = 0;
+
+    while records.skip_record()? {
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+// Reads the expiry times of a table, streaming past its records to reach them
+pub(crate) fn read_expires(table: &str) -> Result<BTreeMap<String, u64>> {
+    let mut scanner = open_table(table)?;
+
+    let mut expires = BTreeMap::new();
+
+    if !scanner.seek_object("expires")? {
+        return Ok(expires);
+    }
+
+    let mut key = Vec::new();
+    let mut value = Vec::new();
+
+    loop {
+        scanner.skip_whitespace()?;
+
+        match scanner.peek()? {
+            Some(b'}') => return Ok(expires),
+            Some(b',') if !key.is_empty() => scanner.consume(),
+            Some(b'"') if key.is_empty() => {}
+            _ => return Err(malformed("expected an expiry time")),
+        }
+
+        key.clear();
+        scanner.capture_value(&mut key)?;
+
+        scanner.expect(b':')?;
+
+        value.clear();
+        scanner.capture_value(&mut value)?;
+
+        expires.insert(
+            serde_json::from_slice(&key)?,
+            serde_json::from_slice(&value)?,
+        );
+    }
+}
+
+fn open_table(table: &str) -> Result<Scanner<BufReader<File>>> {
+    match File::open(db_table(table)) {
+        Ok(file) => Ok(Scanner {
+            reader: BufReader::new(file),
+        }),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            Err(Error::NoSuchTable(table.to_owned()))
+        }
+        Err(err) => Err(Error::Io(err)),
+    }
+}
+
+// Finds the unparsed JSON of one record in a table's contents, stepping over every other record
+// without parsing it
+pub(crate) fn find_raw<'a>(contents: &'a str, id: &str) -> Result<Option<&'a str>> {
+    find_raw_in(contents, "records", id)
+}
+
+// Like `find_raw`, but looks in another object at the top level of the table, keyed by id
+pub(crate) fn find_raw_in<'a>(contents: &'a str, field: &str, id: &str) -> Result<Option<&'a str>> {
+    Ok(find_raw_many(contents, Some(field), &[id.to_string()])?.remove(id))
+}
+
+// Like `find_raw_in`, but finds several ids in one pass. Without a field, `contents` is itself
+// the object the ids are keys of. Keys are unescaped before they are compared, and the whole
+// object is read, as a key found twice means its last value, as it does to serde.
+pub(crate) fn find_raw_many<'a>(
+    contents: &'a str,
+    field: Option<&str>,
+    ids: &[String],
+) -> Result<BTreeMap<String, &'a str>> {
+    let wanted: BTreeSet<&str> = ids.iter().map(String::as_str).collect();
+
+    let mut found = BTreeMap::new();
+
+    let mut scanner = Scanner {
+        reader: contents.as_bytes(),
+    };
+
+    match field {
+        Some(field) if !scanner.seek_object(field)? => return Ok(found),
+        Some(_) => {}
+        None => scanner.expect(b'{')?,
+    }
+
+    let mut key = Vec::new();
+
+    loop {
+        scanner.skip_whitespace()?;
+
+        match scanner.peek()? {
+            Some(b'}') => break,
+            Some(b',') if !key.is_empty() => scanner.consume(),
+            Some(b'"') if key.is_empty() => {}
+            _ => return Err(malformed("expected a record")),
+        }
+
+        key.clear();
+        scanner.capture_value(&mut key)?;
+
+        scanner.expect(b':')?;
+        scanner.skip_whitespace()?;
+
+        let start = contents.len() - scanner.reader.len();
+
+        scanner.capture_value(&mut io::sink())?;
+
+        let id: String = serde_json::from_slice(&key)?;
+
+        if wanted.contains(id.as_str()) {
+            let end = contents.len() - scanner.reader.len();
+
+            found.insert(id, &contents[start..end]);
+        }
+    }
+
+    Ok(found)
+}
+
+// Finds the unparsed ids an index files under `key`, streaming the index file rather than
+// reading it whole. The entries of an index are written in key order, so the scan stops at the
+// first key past `key`.
+pub(crate) fn find_entry(file: File, name: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    let mut scanner = Scanner {
+        reader: BufReader::new(file),
+    };
+
+    if !scanner.seek_object(name)? || !scanner.seek_field("entries")? {
+        return Err(Error::NoSuchIndex(name.to_string()));
+    }
+
+    let mut found = Vec::new();
+    let mut first = true;
+
+    loop {
+        scanner.skip_whitespace()?;
+
+        match scanner.peek()? {
+            Some(b'}') => return Ok(None),
+            Some(b',') if !first => scanner.consume(),
+            Some(b'"') if first => {}
+            _ => return Err(malformed("expected an index entry")),
+        }
+
+        first = false;
+
+        found.clear();
+        scanner.capture_value(&mut found)?;
+
+        let entry: String = serde_json::from_slice(&found)?;
+
+        scanner.expect(b':')?;
+
+        match entry.as_str().cmp(key) {
+            Ordering::Less => scanner.capture_value(&mut io::sink())?,
+            Ordering::Equal => {
+                found.clear();
+                scanner.capture_value(&mut found)?;
+
+                return Ok(Some(found));
+            }
+            Ordering::Greater => return Ok(None),
+        }
+    }
+}
+
+impl<T> RecordIter<T> {
+    // Moves to the next record that has not expired, returning its id and leaving its raw value
+    // in the buffer
+    fn advance(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.advance_any()? {
+                Some(id) if self.expired.contains(&id) => continue,
+                id => return Ok(id),
+            }
+        }
+    }
+
+    fn advance_any(&mut self) -> Result<Option<String>> {
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.scanner.skip_whitespace()?;
+
+        match self.scanner.peek()? {
+            Some(b'}') => {
+                self.state = State::Done;
+
+                return Ok(None);
+            }
+            Some(b',') if self.state == State::Next => self.scanner.consume(),
+            Some(b'"') if self.state == State::First => {}
+            _ => return Err(malformed("expected a record")),
+        }
+
+        self.state = State::Next;
+
+        self.buffer.clear();
+        self.scanner.capture_value(&mut self.buffer)?;
+
+        let id: String = serde_json::from_slice(&self.buffer)?;
+
+        self.scanner.expect(b':')?;
+
+        self.buffer.clear();
+        self.scanner.capture_value(&mut self.buffer)?;
+
+        Ok(Some(id))
+    }
+
+    fn skip_record(&mut self) -> Result<bool> {
+        let result = self.advance();
+
+        if result.is_err() {
+            self.state = State::Done;
+        }
+
+        Ok(result?.is_some())
+    }
+}
+
+impl<T> Iterator for RecordIter<T>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    type Item = Result<(String, T)>;
+
+    fn next(&mut self) -> Option<Result<(String, T)>> {
+        let record = self.advance().and_then(|id| match id {
+            Some(id) => Ok(Some((id, serde_json::from_slice(&self.buffer)?))),
+            None => Ok(None),
+        });
+
+        if record.is_err() {
+            self.state = State::Done;
+        }
+
+        record.transpose()
+    }
+}
+
+// Scanner ****************************************************************************************
+
+struct Scanner<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Scanner<R> {
+    fn peek(&mut self) -> Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let byte = self
+            .peek()?
+            .ok_or_else(|| malformed("unexpected end of table"))?;
+
+        self.reader.consume(1);
+
+        Ok(byte)
+    }
+
+    fn consume(&mut self) {
+        self.reader.consume(1);
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(byte) = self.peek()? {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+
+            self.reader.consume(1);
+        }
+
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        self.skip_whitespace()?;
+
+        match self.next()? {
+            byte if byte == expected => Ok(()),
+            _ => Err(malformed("unexpected character")),
+        }
+    }
+
+    // Walks the top-level object up to the opening brace of the named field, returning false if
+    // it has none
+    fn seek_object(&mut self, field: &str) -> Result<bool> {
+        self.expect(b'{')?;
+
+        self.seek_field(field)
+    }
+
+    // Like `seek_object`, but walks an object whose opening brace is already behind the scanner
+    fn seek_field(&mut self, field: &str) -> Result<bool> {
+        let mut key = Vec::new();
+
+        loop {
+            self.skip_whitespace()?;
+
+            if self.peek()? == Some(b'}') {
+                return Ok(false);
+            }
+
+            key.clear();
+            self.capture_value(&mut key)?;
+
+            self.expect(b':')?;
+
+            if serde_json::from_slice::<String>(&key)? == field {
+                self.expect(b'{')?;
+
+                return Ok(true);
+            }
+
+            self.capture_value(&mut io::sink())?;
+
+            self.skip_whitespace()?;
+
+            match self.next()? {
+                b',' => continue,
+                b'}' => return Ok(false),
+                _ => return Err(malformed("expected a field")),
+            }
+        }
+    }
+
+    // Copies one complete JSON value, whatever its kind, into `out`
+    fn capture_value<W: io::Write>(&mut self, out: &mut W) -> Result<()> {
+        self.skip_whitespace()?;
+
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        loop {
+            let byte = match self.peek()? {
+                Some(byte) => byte,
+                None if depth == 0 && !in_string => return Ok(()),
+                None => return Err(malformed("unexpected end of table")),
+            };
+
+            if in_string {
+                self.reader.consume(1);
+                out.write_all(&[byte])?;
+
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        in_string = false;
+
+                        if depth == 0 {
+                            return Ok(());
+                        }
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            match byte {
+                b'"' => in_string = true,
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' if depth == 0 => return Ok(()),
+                b'}' | b']' => depth -= 1,
+                b',' | b':' if depth == 0 => return Ok(()),
+                _ if byte.is_ascii_whitespace() && depth == 0 => return Ok(()),
+                _ => {}
+            }
+
+            self.reader.consume(1);
+            out.write_all(&[byte])?;
+
+            if depth == 0 && (byte == b'}' || byte == b']') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::Serde(serde_json::Error::custom(format!(
+        "malformed table: {}",
+        msg
+    )))
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_stream {
+    use super::*;
+    use crate::{batch_insert, create_empty_table, drop_table, update_json};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Note {
+        pub text: String,
+        pub tags: Vec<String>,
+    }
+
+    fn note(text: &str) -> Note {
+        Note {
+            text: text.to_string(),
+            tags: vec!["a,b".to_string(), "{\"}".to_string()],
+        }
+    }
+
+    #[test]
+    fn can_stream_records_in_order() -> Result<()> {
+        let table = "stream_order_test";
+
+        create_empty_table::<Note>(table)?;
+
+        batch_insert(
+            table,
+            (0..12).map(|n| note(&format!("note {}", n))).collect(),
+        )?;
+
+        let records: Vec<(String, Note)> = iter_records::<Note>(table)?.collect::<Result<_>>()?;
+
+        assert_eq!(records.len(), 12);
+        assert_eq!(records[10], ("10".to_string(), note("note 10")));
+
+        let found = stream_find_by::<Note, _>(table, |n| n.text.ends_with('1'))?;
+
+        assert_eq!(found.keys().collect::<Vec<_>>(), vec!["1", "11"]);
+
+        assert_eq!(stream_count_records(table)?, 12);
+
+        drop_table(table)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_find_one_raw_record() -> Result<()> {
+        let contents = "{\"table\":\"t\",\"next_id\":\"3\",\"records\":{\"0\":{\"a\":\"}\\\"\"}, \"a\\\"b\": [1, {\"c\": null}],\"2\":7}}";
+
+        assert_eq!(find_raw(contents, "0")?, Some("{\"a\":\"}\\\"\"}"));
+        assert_eq!(find_raw(contents, "a\"b")?, Some("[1, {\"c\": null}]"));
+        assert_eq!(find_raw(contents, "2")?, Some("7"));
+        assert_eq!(find_raw(contents, "1")?, None);
+
+        let contents = "{\"records\":{\"\\u0030\":1,\"\\/x\":2,\"2\":3,\"2\":4}}";
+
+        assert_eq!(find_raw(contents, "0")?, Some("1"));
+        assert_eq!(find_raw(contents, "/x")?, Some("2"));
+        assert_eq!(find_raw(contents, "2")?, Some("4"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_stream_empty_and_malformed_tables() -> Result<()> {
+        let table = "stream_empty_test";
+
+        create_empty_table::<Note>(table)?;
+
+        assert_eq!(iter_records::<Note>(table)?.count(), 0);
+
+        assert_eq!(stream_count_records(table)?, 0);
+
+        update_json(table, "not a table")?;
+
+        assert!(matches!(iter_records::<Note>(table), Err(Error::Serde(_))));
+
+        drop_table(table)?;
+
+        assert!(matches!(
+            iter_records::<Note>(table),
+            Err(Error::NoSuchTable(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,832 @@
+// Copyright 2016 The Rust_Bucket Project Developers. See the COPYRIGHT file at
+// the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option. This
+// file may not be copied, modified, or distributed except according to those
+// terms.
+
+//! Transaction module.
+//!
+//! A transaction stages its writes in memory and commits them in one go. The new contents of
+//! every table it touched are first written to a journal, which is synced to disk and then put in
+//! place with a rename. Only then are the tables themselves replaced. Should the process die part
+//! way through, `recover` finishes the job from the journal, so either every table changes or
+//! none does. The journal is only removed once the tables are synced to disk, and one left behind
+//! is always finished before the next write. Hooks run as they would for the same writes made one
+//! at a time. Renaming a table is recorded the same way before any file moves, so a rename cut
+//! short is finished too.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::errors::{Error, Result};
+use super::{
+    Change, DB_PATH, Records, TableData, backup, constraints, db_table, get_table, hooks,
+    lock_for_recovery, lock_writes, mirror, references, reindex, schema,
+};
+
+thread_local! {
+    // Whether this thread is running the closure of a transaction
+    static OPEN: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) struct Staged {
+    pub(crate) data: TableData<Value>,
+    pub(crate) changes: Vec<Change>,
+    // How many of the changes the before hooks and checks have been run over
+    checked: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal<D> {
+    tables: BTreeMap<String, D>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Rename {
+    from: String,
+    to: String,
+}
+
+/// Writes staged by a running transaction, handed to the closure given to `transaction`.
+///
+/// Reads through a transaction see its own staged writes. Writes made inside the closure through
+/// the plain functions of this crate fail with `Error::TransactionOpen`, as committing the
+/// transaction would write over them.
+pub struct Transaction {
+    pub(crate) staged: BTreeMap<String, Staged>,
+}
+
+/// Runs `f` as one transaction across any number of tables.
+///
+/// If `f` returns `Ok`, everything it staged is committed atomically. If it returns `Err`, or
+/// committing fails a constraint, nothing is written and the error is returned. No other write
+/// in this process can interleave with a running transaction.
+///
+/// Records deleted or nulled by an `OnDelete::Cascade` or `OnDelete::SetNull` reference are
+/// staged along with the rest and committed in the same journal.
+pub fn transaction<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce(&mut Transaction) -> Result<R>,
+{
+    let _lock = lock_writes()?;
+
+    let mut tx = Transaction {
+        staged: BTreeMap::new(),
+    };
+
+    let result = {
+        let _open = Open::enter();
+
+        f(&mut tx)?
+    };
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+/// Finishes a transaction or table rename interrupted part way through, returning whether there
+/// was one.
+///
+/// Called before every write, so it only needs calling by hand to repair the tables before
+/// anything else reads them.
+pub fn recover() -> Result<bool> {
+    let _lock = lock_for_recovery();
+
+    backup::recover_restore()?;
+
+    let renamed = match File::open(rename_path()) {
+        Ok(file) => {
+            let rename: Rename = serde_json::from_reader(io::BufReader::new(file))?;
+
+            super::finish_rename(&rename.from, &rename.to)?;
+
+            remove_synced(&rename_path())?;
+
+            true
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    let journal: Journal<TableData<Value>> = match File::open(journal_path()) {
+        Ok(file) => serde_json::from_reader(io::BufReader::new(file))?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(renamed),
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    for (table, data) in &journal.tables {
+        super::upgrade_table(table, data)?;
+    }
+
+    sync_tables(journal.tables.keys())?;
+
+    // The indexes may or may not have caught up, so rebuild them from scratch. Subscribers were
+    // either told already or never will be, as the write they would hear about is not a new one.
+    for (table, data) in journal.tables {
+        let mut changes = vec![Change::Clear];
+
+        for (id, new) in data.records {
+            changes.push(Change::Insert { id, new });
+        }
+
+        reindex(&table, &changes)?;
+    }
+
+    remove_journal()?;
+
+    Ok(true)
+}
+
+impl Transaction {
+    pub fn get_table<T>(&mut self, table: &str) -> Result<TableData<T>>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let data = &self.stage(table)?.data;
+
+        Ok(TableData {
+            table: data.table.clone(),
+            next_id: data.next_id.clone(),
+            versions: data.versions.clone(),
+            expires: data.expires.clone(),
+            schema_version: data.schema_version,
+            records: self.get_table_records(table)?,
+        })
+    }
+
+    pub fn get_table_records<T>(&mut self, table: &str) -> Result<Records<T>>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let data = &self.stage(table)?.data;
+
+        data.records
+            .iter()
+            .filter(|&(id, _)| data.is_live(id))
+            .map(|(id, record)| Ok((id.clone(), T::deserialize(record)?)))
+            .collect()
+    }
+
+    pub fn find<T>(&mut self, table: &str, id: &str) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let data = &self.stage(table)?.data;
+
+        match data.records.get(id) {
+            Some(record) if data.is_live(id) => T::deserialize(record).map_err(Error::from),
+            _ => Err(Error::NoSuchKey),
+        }
+    }
+
+    pub fn append_records<T>(&mut self, table: &str, t: T) -> Result<()>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        self.batch_insert(table, vec![t])
+    }
+
+    pub fn batch_insert<T>(&mut self, table: &str, records: Vec<T>) -> Result<()>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let staged = self.stage(table)?;
+
+        let mut next_id = staged.data.next_id.parse::<i32>()?;
+
+        for record in records {
+            let id = next_id.to_string();
+
+            let new = serde_json::to_value(record)?;
+
+            staged.data.records.insert(id.clone(), new.clone());
+            staged.changes.push(Change::Insert { id, new });
+
+            next_id += 1;
+        }
+
+        staged.data.next_id = next_id.to_string();
+
+        Ok(())
+    }
+
+    pub fn update_record<T>(&mut self, table: &str, id: &str, record: T) -> Result<()>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let staged = self.stage(table)?;
+
+        let new = serde_json::to_value(record)?;
+
+        if !staged.data.is_live(id) {
+            return Err(Error::NoSuchKey);
+        }
+
+        let old = match staged.data.records.get_mut(id) {
+            Some(old) => std::mem::replace(old, new.clone()),
+            None => return Err(Error::NoSuchKey),
+        };
+
+        staged.changes.push(Change::Update {
+            id: id.to_string(),
+            old,
+            new,
+        });
+
+        Ok(())
+    }
+
+    pub fn delete<T>(&mut self, table: &str, id: &str) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a> + Serialize,
+    {
+        let staged = self.stage(table)?;
+
+        if !staged.data.is_live(id) {
+            return Err(Error::NoSuchKey);
+        }
+
+        let old = staged.data.records.remove(id).ok_or(Error::NoSuchKey)?;
+
+        let record = T::deserialize(&old)?;
+
+        staged.changes.push(Change::Delete {
+            id: id.to_string(),
+            old,
+        });
+
+        Ok(record)
+    }
+
+    pub fn clear_table(&mut self, table: &str) -> Result<()> {
+        let staged = self.stage(table)?;
+
+        staged.data.records.clear();
+        staged.data.next_id = "0".to_string();
+        staged.changes.push(Change::Clear);
+
+        Ok(())
+    }
+
+    pub(crate) fn stage(&mut self, table: &str) -> Result<&mut Staged> {
+        if !self.staged.contains_key(table) {
+            let staged = Staged {
+                data: get_table::<Value>(table)?,
+                changes: Vec::new(),
+                checked: 0,
+            };
+
+            self.staged.insert(table.to_string(), staged);
+        }
+
+        Ok(self
+            .staged
+            .get_mut(table)
+            .expect("the table was staged just above"))
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        // Cascades stage more changes as they go, which are then run through the same steps
+        while let Some(table) = self.unchecked() {
+            let staged = self
+                .staged
+                .get_mut(&table)
+                .expect("the table has unchecked changes");
+
+            let from = staged.checked;
+
+            hooks::before(&table, &mut staged.data, &mut staged.changes[from..])?;
+
+            schema::check(&table, &staged.changes[from..])?;
+
+            constraints::check(&table, &staged.data, &staged.changes[from..])?;
+
+            staged.checked = staged.changes.len();
+
+            references::cascade(self, &table, from)?;
+        }
+
+        references::check(self)?;
+
+        for staged in self.staged.values_mut() {
+            staged.data.record_changes(&staged.changes);
+        }
+
+        let journal = Journal {
+            tables: self
+                .staged
+                .iter()
+                .filter(|(_, staged)| !staged.changes.is_empty())
+                .map(|(table, staged)| (table.clone(), &staged.data))
+                .collect(),
+        };
+
+        if journal.tables.is_empty() {
+            return Ok(());
+        }
+
+        write_journal(&journal)?;
+
+        // The transaction is committed once its journal is written. Should putting it in place
+        // fail, it is finished from the journal instead, here or else before the next write.
+        if let Err(err) = self.apply(&journal) {
+            recover().map_err(|_| err)?;
+        }
+
+        for (table, staged) in &self.staged {
+            hooks::after(table, &staged.changes);
+        }
+
+        Ok(())
+    }
+
+    fn apply(&self, journal: &Journal<&TableData<Value>>) -> Result<()> {
+        for (table, data) in &journal.tables {
+            super::upgrade_table(table, data)?;
+        }
+
+        // The journal is the only way back should the tables not make it to disk
+        sync_tables(journal.tables.keys())?;
+
+        for (table, staged) in &self.staged {
+            if !staged.changes.is_empty() {
+                mirror(table, &staged.changes)?;
+            }
+        }
+
+        remove_journal()
+    }
+
+    // A table with changes the before hooks and checks have yet to see
+    fn unchecked(&self) -> Option<String> {
+        self.staged
+            .iter()
+            .find(|(_, staged)| staged.checked < staged.changes.len())
+            .map(|(table, _)| table.clone())
+    }
+}
+
+// Fails while this thread runs the closure of a transaction, so a plain write cannot slip in
+// underneath it
+pub(crate) fn refuse_if_open() -> Result<()> {
+    match OPEN.get() {
+        true => Err(Error::TransactionOpen),
+        false => Ok(()),
+    }
+}
+
+// Marks the closure of a transaction as running on this thread until dropped
+struct Open;
+
+impl Open {
+    fn enter() -> Open {
+        OPEN.set(true);
+
+        Open
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        OPEN.set(false);
+    }
+}
+
+// Renames a table, recording the rename first so that `recover` finishes it should it be cut
+// short
+pub(crate) fn rename_table(from: &str, to: &str) -> Result<()> {
+    let rename = Rename {
+        from: from.to_string(),
+        to: to.to_string(),
+    };
+
+    write_synced(&rename_path(), &rename)?;
+
+    super::finish_rename(from, to)?;
+
+    remove_synced(&rename_path())
+}
+
+// Commits a write to one table as a transaction, so whatever it cascades to is committed along
+// with it, then hands the table back as it was written
+pub(crate) fn commit_table<T>(
+    table: &str,
+    data: &mut TableData<T>,
+    changes: &[Change],
+) -> Result<()>
+where
+    T: for<'a> Deserialize<'a> + Serialize,
+{
+    let mut tx = Transaction {
+        staged: BTreeMap::new(),
+    };
+
+    let staged = Staged {
+        data: retype(data)?,
+        changes: changes.to_vec(),
+        checked: 0,
+    };
+
+    tx.staged.insert(table.to_string(), staged);
+
+    tx.commit()?;
+
+    *data = retype(&tx.staged[table].data)?;
+
+    Ok(())
+}
+
+// Going through the text rather than a value keeps raw records working
+fn retype<T, U>(data: &TableData<T>) -> Result<TableData<U>>
+where
+    T: Serialize,
+    U: for<'a> Deserialize<'a> + Serialize,
+{
+    serde_json::from_str(&serde_json::to_string(data)?).map_err(Error::from)
+}
+
+fn journal_path() -> PathBuf {
+    Path::new(DB_PATH).join(".journal")
+}
+
+fn rename_path() -> PathBuf {
+    Path::new(DB_PATH).join(".rename")
+}
+
+// The journal only appears under its real name once it is completely on disk, which is the
+// moment the transaction commits
+fn write_journal<D: Serialize>(journal: &Journal<D>) -> Result<()> {
+    write_synced(&journal_path(), journal)
+}
+
+fn remove_journal() -> Result<()> {
+    remove_synced(&journal_path())
+}
+
+fn write_synced<D: Serialize>(path: &Path, d: &D) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let file = File::create(&tmp)?;
+
+    let mut writer = BufWriter::new(file);
+
+    serde_json::to_writer(&mut writer, d)?;
+
+    writer.flush()?;
+
+    writer
+        .into_inner()
+        .map_err(|err| Error::Io(err.into_error()))?
+        .sync_all()?;
+
+    fs::rename(tmp, path)?;
+
+    sync_dir(Path::new(DB_PATH))
+}
+
+fn remove_synced(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+
+    sync_dir(Path::new(DB_PATH))
+}
+
+// Flushes the tables, and the directories they were renamed into, all the way to the disk
+fn sync_tables<'a, I: Iterator<Item = &'a String>>(tables: I) -> Result<()> {
+    let mut dirs = BTreeSet::new();
+
+    for table in tables {
+        let path = db_table(table);
+
+        File::open(&path)?.sync_all()?;
+
+        if let Some(dir) = path.parent() {
+            dirs.insert(dir.to_path_buf());
+        }
+    }
+
+    for dir in dirs {
+        sync_dir(&dir)?;
+    }
+
+    Ok(())
+}
+
+// A rename only lasts once the directory holding it is synced, which needs the directory opened
+// as a file, as only Unix allows
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Tests ******************************************************************************************
+
+#[cfg(test)]
+mod the_transaction {
+    use super::*;
+    use crate::{
+        OnDelete, add_reference, add_unique_constraint, append_records, create_empty_table,
+        create_index, drop_table, find, find_by_index, index, sidecar, subscribe, table_exists,
+    };
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Stock {
+        pub item: String,
+        pub count: i32,
+    }
+
+    fn stock(item: &str, count: i32) -> Stock {
+        Stock {
+            item: item.to_string(),
+            count,
+        }
+    }
+
+    fn create_warehouses(from: &str, to: &str) -> Result<()> {
+        create_empty_table::<Stock>(from)?;
+        create_empty_table::<Stock>(to)?;
+
+        append_records(from, stock("bolt", 10))?;
+        append_records(to, stock("bolt", 0))
+    }
+
+    fn move_stock(tx: &mut Transaction, from: &str, to: &str, count: i32) -> Result<()> {
+        let mut source: Stock = tx.find(from, "0")?;
+        let mut target: Stock = tx.find(to, "0")?;
+
+        source.count -= count;
+        target.count += count;
+
+        tx.update_record(from, "0", source)?;
+        tx.update_record(to, "0", target)
+    }
+
+    #[test]
+    fn can_commit_across_tables() -> Result<()> {
+        let (from, to) = ("tx_commit_from", "tx_commit_to");
+
+        create_warehouses(from, to)?;
+
+        create_index(to, "item", "item")?;
+
+        let seen = transaction(|tx| {
+            move_stock(tx, from, to, 4)?;
+
+            tx.append_records(to, stock("nut", 3))?;
+
+            tx.find::<Stock>(to, "0")
+        })?;
+
+        assert_eq!(seen, stock("bolt", 4));
+
+        assert_eq!(find::<Stock>(from, "0")?, stock("bolt", 6));
+        assert_eq!(find::<Stock>(to, "0")?, stock("bolt", 4));
+
+        let nuts = find_by_index::<Stock, _>(to, "item", &"nut")?;
+
+        assert_eq!(nuts.get("1"), Some(&stock("nut", 3)));
+
+        assert!(!journal_path().exists());
+
+        drop_table(from)?;
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_roll_back_on_error() -> Result<()> {
+        let (from, to) = ("tx_rollback_from", "tx_rollback_to");
+
+        create_warehouses(from, to)?;
+
+        let result: Result<()> = transaction(|tx| {
+            move_stock(tx, from, to, 4)?;
+
+            tx.delete::<Stock>(from, "7").map(|_| ())
+        });
+
+        assert!(matches!(result, Err(Error::NoSuchKey)));
+
+        add_unique_constraint(to, "item", "item")?;
+
+        let result = transaction(|tx| {
+            move_stock(tx, from, to, 4)?;
+
+            tx.append_records(to, stock("bolt", 1))
+        });
+
+        assert!(matches!(result, Err(Error::UniqueViolation { .. })));
+
+        assert_eq!(find::<Stock>(from, "0")?, stock("bolt", 10));
+        assert_eq!(find::<Stock>(to, "0")?, stock("bolt", 0));
+
+        drop_table(from)?;
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_refuse_plain_writes_inside_a_transaction() -> Result<()> {
+        let (from, to) = ("tx_plain_from", "tx_plain_to");
+
+        create_warehouses(from, to)?;
+
+        let result = transaction(|tx| {
+            move_stock(tx, from, to, 4)?;
+
+            append_records(to, stock("nut", 3))
+        });
+
+        assert!(matches!(result, Err(Error::TransactionOpen)));
+
+        assert_eq!(find::<Stock>(from, "0")?, stock("bolt", 10));
+        assert!(matches!(find::<Stock>(to, "1"), Err(Error::NoSuchKey)));
+
+        append_records(to, stock("nut", 3))?;
+
+        drop_table(from)?;
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_recover_an_interrupted_commit() -> Result<()> {
+        let (from, to) = ("tx_recover_from", "tx_recover_to");
+
+        create_warehouses(from, to)?;
+
+        create_index(to, "item", "item")?;
+
+        // Hold the lock so no other transaction replays the journal first
+        let _lock = lock_writes()?;
+
+        let mut journal = Journal {
+            tables: BTreeMap::new(),
+        };
+
+        let mut data = get_table::<Value>(from)?;
+        data.records.clear();
+        journal.tables.insert(from.to_string(), data);
+
+        let mut data = get_table::<Value>(to)?;
+        data.records
+            .insert("0".to_string(), serde_json::to_value(stock("nut", 2))?);
+        journal.tables.insert(to.to_string(), data);
+
+        // Dies after the journal was written, before any table was
+        write_journal(&journal)?;
+
+        let events = Arc::new(AtomicUsize::new(0));
+
+        let counted = events.clone();
+
+        let subscription = subscribe(Some(to), move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(recover()?);
+        assert!(!recover()?);
+
+        drop(subscription);
+
+        assert_eq!(events.load(Ordering::SeqCst), 0);
+
+        assert!(matches!(find::<Stock>(from, "0"), Err(Error::NoSuchKey)));
+        assert_eq!(find::<Stock>(to, "0")?, stock("nut", 2));
+
+        assert!(find_by_index::<Stock, _>(to, "item", &"bolt")?.is_empty());
+        assert_eq!(find_by_index::<Stock, _>(to, "item", &"nut")?.len(), 1);
+
+        drop_table(from)?;
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_finish_an_interrupted_rename() -> Result<()> {
+        let (from, to) = ("tx_rename_from", "tx_rename_to");
+
+        create_empty_table::<Stock>(from)?;
+
+        append_records(from, stock("bolt", 10))?;
+
+        create_index(from, "item", "item")?;
+
+        let _lock = lock_writes()?;
+
+        let rename = Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+
+        // Dies once the rename is recorded, with the index already moved
+        write_synced(&rename_path(), &rename)?;
+
+        fs::rename(sidecar(from, index::SIDECAR), sidecar(to, index::SIDECAR))?;
+
+        assert!(recover()?);
+        assert!(!recover()?);
+
+        assert!(!table_exists(from));
+        assert_eq!(find::<Stock>(to, "0")?, stock("bolt", 10));
+        assert_eq!(find_by_index::<Stock, _>(to, "item", &"bolt")?.len(), 1);
+
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_finish_a_journal_before_the_next_write() -> Result<()> {
+        let (from, to) = ("tx_stale_from", "tx_stale_to");
+
+        create_warehouses(from, to)?;
+
+        {
+            let _lock = lock_writes()?;
+
+            let mut journal = Journal {
+                tables: BTreeMap::new(),
+            };
+
+            let mut data = get_table::<Value>(to)?;
+            data.records
+                .insert("0".to_string(), serde_json::to_value(stock("nut", 2))?);
+            journal.tables.insert(to.to_string(), data);
+
+            write_journal(&journal)?;
+        }
+
+        append_records(to, stock("bolt", 1))?;
+
+        assert!(!journal_path().exists());
+
+        assert_eq!(find::<Stock>(to, "0")?, stock("nut", 2));
+        assert_eq!(find::<Stock>(to, "1")?, stock("bolt", 1));
+
+        drop_table(from)?;
+        drop_table(to)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_cascade_inside_a_transaction() -> Result<()> {
+        let (from, to) = ("tx_cascade_from", "tx_cascade_to");
+
+        create_warehouses(from, to)?;
+
+        add_reference(to, "count", from, OnDelete::Cascade)?;
+
+        let result = transaction(|tx| {
+            tx.delete::<Stock>(from, "0")?;
+
+            tx.append_records(to, stock("nut", 7))
+        });
+
+        assert!(matches!(result, Err(Error::ReferenceViolation { .. })));
+
+        assert_eq!(find::<Stock>(to, "0")?, stock("bolt", 0));
+
+        transaction(|tx| {
+            tx.append_records(from, stock("nut", 5))?;
+
+            tx.delete::<Stock>(from, "0").map(|_| ())
+        })?;
+
+        assert!(matches!(find::<Stock>(to, "0"), Err(Error::NoSuchKey)));
+        assert_eq!(find::<Stock>(from, "1")?, stock("nut", 5));
+
+        drop_table(to)?;
+        drop_table(from)?;
+
+        Ok(())
+    }
+}